@@ -0,0 +1,37 @@
+use crate::api::{Ab, AbDiff, AbPeerChange};
+
+/// What changed from `from` to `to`. Peers are matched by id.
+pub fn diff(from: &Ab, to: &Ab) -> AbDiff {
+    let mut diff = AbDiff::default();
+
+    for peer in &to.peers {
+        match from.peers.iter().find(|from_peer| from_peer.id == peer.id) {
+            None => diff.peers_added.push(peer.clone()),
+            Some(from_peer) if from_peer != peer => diff.peers_changed.push(AbPeerChange {
+                before: from_peer.clone(),
+                after: peer.clone(),
+            }),
+            Some(_) => {},
+        }
+    }
+
+    diff.peers_removed = from.peers
+        .iter()
+        .filter(|peer| !to.peers.iter().any(|to_peer| to_peer.id == peer.id))
+        .cloned()
+        .collect();
+
+    diff.tags_added = to.tags
+        .iter()
+        .filter(|tag| !from.tags.contains(tag))
+        .cloned()
+        .collect();
+
+    diff.tags_removed = from.tags
+        .iter()
+        .filter(|tag| !to.tags.contains(tag))
+        .cloned()
+        .collect();
+
+    diff
+}
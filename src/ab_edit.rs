@@ -0,0 +1,199 @@
+use std::fmt;
+use serde_json::{Map, Value};
+use crate::api::{Ab, AbPeer, AbTag};
+
+/// Clients keep tag colors here, as a JSON-encoded map from tag to ARGB.
+const TAG_COLORS: &str = "tag_colors";
+
+/// An incremental change which doesn't apply to the current version of the book.
+#[derive(Debug)]
+pub enum AbEditError {
+    PeerExists(String),
+    PeerNotFound(String),
+    TagExists(String),
+    TagNotFound(String),
+    MalformedPeer(String),
+}
+
+impl fmt::Display for AbEditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PeerExists(id) => write!(f, "Peer {} already exists", id),
+            Self::PeerNotFound(id) => write!(f, "Peer {} not found", id),
+            Self::TagExists(name) => write!(f, "Tag {} already exists", name),
+            Self::TagNotFound(name) => write!(f, "Tag {} not found", name),
+            Self::MalformedPeer(err) => write!(f, "Malformed peer: {}", err),
+        }
+    }
+}
+
+/// Tag colors as clients store them, values are expected to be ARGB numbers.
+pub fn tag_colors(ab: &Ab) -> Map<String, Value> {
+    ab.other
+        .get(TAG_COLORS)
+        .and_then(Value::as_str)
+        .and_then(|colors| serde_json::from_str(colors).ok())
+        .unwrap_or_default()
+}
+
+/// Change the tag colors. They are only written back if they changed, so a book keeps its ETag otherwise.
+fn edit_tag_colors<F: FnOnce(&mut Map<String, Value>)>(ab: &mut Ab, edit: F) {
+    let mut colors = tag_colors(ab);
+    let before = colors.clone();
+
+    edit(&mut colors);
+
+    if colors != before {
+        // Nothing in a map of JSON values can fail to serialize.
+        ab.other.insert(TAG_COLORS.to_string(), Value::String(serde_json::to_string(&colors).unwrap()));
+    }
+}
+
+/// Tags a peer refers to belong to the book.
+fn add_missing_tags(ab: &mut Ab, tags: &[String]) {
+    for tag in tags {
+        if !ab.tags.contains(tag) {
+            ab.tags.push(tag.clone());
+        }
+    }
+}
+
+pub fn add_peer(ab: &mut Ab, peer: AbPeer) -> Result<(), AbEditError> {
+    if ab.peers.iter().any(|p| p.id == peer.id) {
+        return Err(AbEditError::PeerExists(peer.id));
+    }
+
+    add_missing_tags(ab, &peer.tags);
+    ab.peers.push(peer);
+
+    Ok(())
+}
+
+/// Replace the fields present in `fields`, which must include the peer's `id`.
+pub fn update_peer(ab: &mut Ab, fields: Map<String, Value>) -> Result<(), AbEditError> {
+    let id = fields
+        .get("id")
+        .and_then(Value::as_str)
+        .ok_or_else(|| AbEditError::MalformedPeer("missing id".to_string()))?
+        .to_string();
+
+    let index = ab.peers
+        .iter()
+        .position(|peer| peer.id == id)
+        .ok_or(AbEditError::PeerNotFound(id))?;
+
+    let mut value = serde_json::to_value(&ab.peers[index]).map_err(|err| AbEditError::MalformedPeer(err.to_string()))?;
+    if let Value::Object(peer) = &mut value {
+        peer.extend(fields);
+    }
+
+    let peer: AbPeer = serde_json::from_value(value).map_err(|err| AbEditError::MalformedPeer(err.to_string()))?;
+
+    add_missing_tags(ab, &peer.tags);
+    ab.peers[index] = peer;
+
+    Ok(())
+}
+
+/// Peers which are already gone are skipped.
+pub fn delete_peers(ab: &mut Ab, ids: &[String]) {
+    ab.peers.retain(|peer| !ids.contains(&peer.id));
+}
+
+pub fn add_tag(ab: &mut Ab, tag: AbTag) -> Result<(), AbEditError> {
+    if ab.tags.contains(&tag.name) {
+        return Err(AbEditError::TagExists(tag.name));
+    }
+
+    edit_tag_colors(ab, |colors| {
+        colors.insert(tag.name.clone(), Value::from(tag.color));
+    });
+
+    ab.tags.push(tag.name);
+
+    Ok(())
+}
+
+/// Rename a tag, on the peers too.
+pub fn rename_tag(ab: &mut Ab, old: &str, new: &str) -> Result<(), AbEditError> {
+    if ab.tags.iter().any(|tag| tag == new) {
+        return Err(AbEditError::TagExists(new.to_string()));
+    }
+
+    let tag = ab.tags
+        .iter_mut()
+        .find(|tag| *tag == old)
+        .ok_or_else(|| AbEditError::TagNotFound(old.to_string()))?;
+    *tag = new.to_string();
+
+    for peer in ab.peers.iter_mut() {
+        for tag in peer.tags.iter_mut().filter(|tag| *tag == old) {
+            *tag = new.to_string();
+        }
+    }
+
+    edit_tag_colors(ab, |colors| {
+        if let Some(color) = colors.remove(old) {
+            colors.insert(new.to_string(), color);
+        }
+    });
+
+    Ok(())
+}
+
+pub fn update_tag(ab: &mut Ab, tag: AbTag) -> Result<(), AbEditError> {
+    if !ab.tags.contains(&tag.name) {
+        return Err(AbEditError::TagNotFound(tag.name));
+    }
+
+    edit_tag_colors(ab, |colors| {
+        colors.insert(tag.name, Value::from(tag.color));
+    });
+
+    Ok(())
+}
+
+/// Delete tags, from the peers too. Tags which are already gone are skipped.
+pub fn delete_tags(ab: &mut Ab, names: &[String]) {
+    ab.tags.retain(|tag| !names.contains(tag));
+
+    for peer in ab.peers.iter_mut() {
+        peer.tags.retain(|tag| !names.contains(tag));
+    }
+
+    edit_tag_colors(ab, |colors| colors.retain(|tag, _| !names.contains(tag)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ab(colors: &str) -> Ab {
+        let mut ab = Ab { tags: vec!["b".to_string(), "a".to_string()], ..Default::default() };
+        ab.other.insert(TAG_COLORS.to_string(), Value::String(colors.to_string()));
+        ab
+    }
+
+    #[test]
+    fn unchanged_colors_keep_their_encoding() {
+        let mut ab = ab(r#"{"b":1,"a":2}"#);
+        let before = ab.clone();
+
+        update_tag(&mut ab, AbTag { name: "b".to_string(), color: 1 }).unwrap();
+        rename_tag(&mut ab, "c", "d").unwrap_err();
+        delete_tags(&mut ab, &["c".to_string()]);
+
+        assert_eq!(ab, before);
+    }
+
+    #[test]
+    fn changed_colors() {
+        let mut ab = ab(r#"{"b":1,"a":2}"#);
+
+        update_tag(&mut ab, AbTag { name: "b".to_string(), color: 3 }).unwrap();
+        rename_tag(&mut ab, "a", "c").unwrap();
+        delete_tags(&mut ab, &["b".to_string()]);
+
+        assert_eq!(ab.other[TAG_COLORS], r#"{"c":2}"#);
+    }
+}
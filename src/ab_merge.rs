@@ -0,0 +1,186 @@
+use std::collections::HashSet;
+use serde_json::{Map, Value};
+use crate::api::{Ab, AbPeer};
+
+/// Three-way merge of two address books which both started from `base`.
+///
+/// Peers are matched by id and merged as a whole: a peer changed (or removed) on one side only takes that side,
+/// a peer changed differently on both sides is a conflict. Tags are merged as sets and never conflict.
+/// Returns `None` on conflict.
+pub fn merge(base: &Ab, ours: &Ab, theirs: &Ab) -> Option<Ab> {
+    let tags = merge_tags(&base.tags, &ours.tags, &theirs.tags);
+    let peers = merge_peers(&base.peers, &ours.peers, &theirs.peers)?;
+    let other = merge_other(&base.other, &ours.other, &theirs.other)?;
+
+    Some(Ab { tags, peers, other })
+}
+
+/// Pick the side which changed, `None` if both changed differently.
+fn merge_value<'a, T: PartialEq>(base: Option<&'a T>, ours: Option<&'a T>, theirs: Option<&'a T>) -> Option<Option<&'a T>> {
+    if ours == theirs || theirs == base {
+        Some(ours)
+    } else if ours == base {
+        Some(theirs)
+    } else {
+        None
+    }
+}
+
+fn merge_tags(base: &[String], ours: &[String], theirs: &[String]) -> Vec<String> {
+    let base: HashSet<&String> = base.iter().collect();
+    let ours_set: HashSet<&String> = ours.iter().collect();
+    let theirs_set: HashSet<&String> = theirs.iter().collect();
+
+    // Set membership can't conflict: if the sides differ, one of them is the base.
+    let keep = |tag: &String| {
+        let in_base = base.contains(tag);
+        let in_ours = ours_set.contains(tag);
+        let in_theirs = theirs_set.contains(tag);
+
+        if in_ours == in_base { in_theirs } else { in_ours }
+    };
+
+    let mut merged: Vec<String> = vec![];
+    for tag in ours.iter().chain(theirs.iter()) {
+        if keep(tag) && !merged.contains(tag) {
+            merged.push(tag.clone());
+        }
+    }
+
+    merged
+}
+
+fn merge_peers(base: &[AbPeer], ours: &[AbPeer], theirs: &[AbPeer]) -> Option<Vec<AbPeer>> {
+    let find = |peers: &'_ [AbPeer], id: &str| -> Option<usize> {
+        peers.iter().position(|peer| peer.id == id)
+    };
+
+    let mut merged: Vec<AbPeer> = vec![];
+    let mut seen: HashSet<&str> = HashSet::new();
+
+    // Our order first, then peers only they added.
+    for peer in ours.iter().chain(theirs.iter()) {
+        if !seen.insert(peer.id.as_str()) {
+            continue;
+        }
+
+        let base_peer = find(base, &peer.id).map(|i| &base[i]);
+        let our_peer = find(ours, &peer.id).map(|i| &ours[i]);
+        let their_peer = find(theirs, &peer.id).map(|i| &theirs[i]);
+
+        if let Some(peer) = merge_value(base_peer, our_peer, their_peer)? {
+            merged.push(peer.clone());
+        }
+    }
+
+    Some(merged)
+}
+
+fn merge_other(base: &Map<String, Value>, ours: &Map<String, Value>, theirs: &Map<String, Value>) -> Option<Map<String, Value>> {
+    let mut merged = Map::new();
+
+    for key in ours.keys().chain(theirs.keys()) {
+        if merged.contains_key(key) {
+            continue;
+        }
+
+        if let Some(value) = merge_value(base.get(key), ours.get(key), theirs.get(key))? {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+
+    Some(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(id: &str, alias: &str) -> AbPeer {
+        AbPeer {
+            id: id.to_string(),
+            username: String::new(),
+            hostname: String::new(),
+            platform: String::new(),
+            alias: alias.to_string(),
+            tags: vec![],
+            hash: String::new(),
+            other: Map::new(),
+        }
+    }
+
+    fn ab(peers: &[AbPeer]) -> Ab {
+        Ab { peers: peers.to_vec(), ..Default::default() }
+    }
+
+    fn ids(ab: &Ab) -> Vec<&str> {
+        ab.peers.iter().map(|peer| peer.id.as_str()).collect()
+    }
+
+    #[test]
+    fn concurrent_adds() {
+        let base = ab(&[peer("1", "a")]);
+        let ours = ab(&[peer("1", "a"), peer("2", "b")]);
+        let theirs = ab(&[peer("1", "a"), peer("3", "c")]);
+
+        let merged = merge(&base, &ours, &theirs).unwrap();
+        assert_eq!(ids(&merged), ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn same_peer_added_alike() {
+        let base = ab(&[]);
+        let ours = ab(&[peer("1", "a")]);
+
+        assert_eq!(merge(&base, &ours, &ours.clone()).unwrap(), ours);
+        assert!(merge(&base, &ours, &ab(&[peer("1", "b")])).is_none());
+    }
+
+    #[test]
+    fn delete_and_edit_elsewhere() {
+        let base = ab(&[peer("1", "a"), peer("2", "b")]);
+        let ours = ab(&[peer("2", "b")]);
+        let theirs = ab(&[peer("1", "a"), peer("2", "edited")]);
+
+        let merged = merge(&base, &ours, &theirs).unwrap();
+        assert_eq!(merged, ab(&[peer("2", "edited")]));
+    }
+
+    #[test]
+    fn deleted_on_both_sides() {
+        let base = ab(&[peer("1", "a"), peer("2", "b")]);
+        let ours = ab(&[peer("2", "b")]);
+
+        assert_eq!(merge(&base, &ours, &ours.clone()).unwrap(), ours);
+    }
+
+    #[test]
+    fn delete_and_edit_same_peer() {
+        let base = ab(&[peer("1", "a")]);
+        let deleted = ab(&[]);
+        let edited = ab(&[peer("1", "edited")]);
+
+        assert!(merge(&base, &deleted, &edited).is_none());
+        assert!(merge(&base, &edited, &deleted).is_none());
+    }
+
+    #[test]
+    fn edit_same_peer() {
+        let base = ab(&[peer("1", "a")]);
+        let ours = ab(&[peer("1", "b")]);
+
+        assert_eq!(merge(&base, &ours, &base).unwrap(), ours);
+        assert_eq!(merge(&base, &base, &ours).unwrap(), ours);
+        assert_eq!(merge(&base, &ours, &ours.clone()).unwrap(), ours);
+        assert!(merge(&base, &ours, &ab(&[peer("1", "c")])).is_none());
+    }
+
+    #[test]
+    fn tags() {
+        let base = Ab { tags: vec!["a".to_string(), "b".to_string()], ..Default::default() };
+        let ours = Ab { tags: vec!["a".to_string(), "c".to_string()], ..Default::default() };
+        let theirs = Ab { tags: vec!["b".to_string(), "d".to_string()], ..Default::default() };
+
+        assert_eq!(merge(&base, &ours, &theirs).unwrap().tags, ["c", "d"]);
+    }
+}
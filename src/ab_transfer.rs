@@ -0,0 +1,248 @@
+use std::borrow::Cow;
+use serde_json::{Map, Value};
+use crate::{
+    api::{Ab, AbPeer, AbImportMode, AbImportReply, AbImportRowError},
+    ab_edit,
+    audit_log::csv_cell,
+};
+
+/// Columns of exported CSV files. Imports take the columns in any order, unknown ones become peer fields as text.
+const CSV_HEADER: &[&str] = &["id", "username", "hostname", "platform", "alias", "tags", "hash", "note"];
+
+/// Separates the tags of a peer in CSV files.
+const CSV_TAG_SEPARATOR: char = ';';
+
+/// Peers to import, each one a set of peer fields.
+#[derive(Debug, Default)]
+pub struct AbImport {
+    /// Tags of the book, for JSON files in the format of the export.
+    tags: Vec<String>,
+    rows: Vec<Map<String, Value>>,
+}
+
+pub fn export_json(ab: &Ab) -> String {
+    // Nothing in `Ab` can fail to serialize.
+    serde_json::to_string_pretty(ab).unwrap()
+}
+
+/// Cells are escaped with `csv_cell`, imports undo it.
+pub fn export_csv(ab: &Ab) -> Option<String> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+
+    writer.write_record(CSV_HEADER).ok()?;
+    for peer in ab.peers.iter() {
+        let note = peer.other.get("note").and_then(Value::as_str).unwrap_or_default();
+        writer.write_record([
+            peer.id.as_str(),
+            peer.username.as_str(),
+            peer.hostname.as_str(),
+            peer.platform.as_str(),
+            peer.alias.as_str(),
+            peer.tags.join(&CSV_TAG_SEPARATOR.to_string()).as_str(),
+            peer.hash.as_str(),
+            note,
+        ].map(|cell| csv_cell(cell).into_owned())).ok()?;
+    }
+
+    String::from_utf8(writer.into_inner().ok()?).ok()
+}
+
+fn file_error(error: String) -> Vec<AbImportRowError> {
+    vec![AbImportRowError { row: 0, error }]
+}
+
+/// Either a list of peers, or a whole book as exported.
+pub fn parse_json(data: &str) -> Result<AbImport, Vec<AbImportRowError>> {
+    let value: Value = serde_json::from_str(data).map_err(|err| file_error(format!("Malformed JSON: {}", err)))?;
+
+    let (tags, peers) = match value {
+        Value::Array(peers) => (vec![], peers),
+        Value::Object(mut ab) => {
+            let tags = match ab.remove("tags") {
+                Some(tags) => serde_json::from_value(tags).map_err(|err| file_error(format!("Malformed tags: {}", err)))?,
+                None => vec![],
+            };
+            match ab.remove("peers") {
+                Some(Value::Array(peers)) => (tags, peers),
+                _ => return Err(file_error("Expected a list of peers in \"peers\"".to_string())),
+            }
+        },
+        _ => return Err(file_error("Expected a list of peers or an address book".to_string())),
+    };
+
+    let mut rows = vec![];
+    let mut errors = vec![];
+
+    for (index, peer) in peers.into_iter().enumerate() {
+        match peer {
+            Value::Object(fields) => rows.push(fields),
+            _ => errors.push(AbImportRowError { row: index + 1, error: "Expected a peer object".to_string() }),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(AbImport { tags, rows })
+}
+
+/// CSV with a header line, see `CSV_HEADER`. Only the columns present are imported.
+pub fn parse_csv(data: &str) -> Result<AbImport, Vec<AbImportRowError>> {
+    let mut reader = csv::Reader::from_reader(data.as_bytes());
+
+    let header: Vec<String> = reader
+        .headers()
+        .map_err(|err| file_error(format!("Malformed CSV header: {}", err)))?
+        .iter()
+        .map(|column| column.trim().to_string())
+        .collect();
+
+    if !header.iter().any(|column| column == "id") {
+        return Err(file_error("Missing \"id\" column".to_string()));
+    }
+
+    let mut rows = vec![];
+    let mut errors = vec![];
+
+    for (index, record) in reader.records().enumerate() {
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                errors.push(AbImportRowError { row: index + 1, error: format!("Malformed CSV: {}", err) });
+                continue;
+            },
+        };
+
+        let mut fields = Map::new();
+        for (column, value) in header.iter().zip(record.iter()) {
+            let value = unescape_csv_cell(value);
+            let value = match column.as_str() {
+                "tags" => Value::Array(
+                    value
+                        .split(CSV_TAG_SEPARATOR)
+                        .map(str::trim)
+                        .filter(|tag| !tag.is_empty())
+                        .map(|tag| Value::String(tag.to_string()))
+                        .collect()
+                ),
+                _ => Value::String(value.to_string()),
+            };
+            fields.insert(column.clone(), value);
+        }
+        rows.push(fields);
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(AbImport { tags: vec![], rows })
+}
+
+/// A cell as it was before `csv_cell` escaped it. A `'` in front of anything `csv_cell` wouldn't escape is kept,
+/// for files which weren't exported here.
+fn unescape_csv_cell(cell: &str) -> &str {
+    match cell.strip_prefix('\'') {
+        Some(value) if matches!(csv_cell(value), Cow::Owned(_)) => value,
+        _ => cell,
+    }
+}
+
+/// Apply parsed peers to the book, all of them or none.
+///
+/// Merging adds new peers and changes the imported fields of existing ones. Replacing makes the imported peers
+/// the only ones, with their tags; other content of the book, like tag colors, is kept.
+pub fn import(ab: &mut Ab, import: AbImport, mode: AbImportMode) -> Result<AbImportReply, Vec<AbImportRowError>> {
+    let mut errors = vec![];
+    let mut peers: Vec<AbPeer> = vec![];
+
+    for (index, fields) in import.rows.iter().enumerate() {
+        let row = index + 1;
+
+        match serde_json::from_value::<AbPeer>(Value::Object(fields.clone())) {
+            Ok(peer) if peer.id.trim().is_empty() => errors.push(AbImportRowError { row, error: "Empty peer id".to_string() }),
+            Ok(peer) if peers.iter().any(|p| p.id == peer.id) => errors.push(AbImportRowError { row, error: format!("Duplicate peer {}", peer.id) }),
+            Ok(peer) => peers.push(peer),
+            Err(err) => errors.push(AbImportRowError { row, error: format!("Malformed peer: {}", err) }),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let exists = |ab: &Ab, id: &str| ab.peers.iter().any(|peer| peer.id == id);
+
+    let mut reply = AbImportReply::default();
+
+    match mode {
+        AbImportMode::Merge => {
+            for (index, (peer, fields)) in peers.into_iter().zip(import.rows).enumerate() {
+                let res = if exists(ab, &peer.id) {
+                    reply.updated += 1;
+                    ab_edit::update_peer(ab, fields)
+                } else {
+                    reply.added += 1;
+                    ab_edit::add_peer(ab, peer)
+                };
+
+                if let Err(err) = res {
+                    errors.push(AbImportRowError { row: index + 1, error: err.to_string() });
+                }
+            }
+
+            for tag in import.tags {
+                if !ab.tags.contains(&tag) {
+                    ab.tags.push(tag);
+                }
+            }
+        },
+        AbImportMode::Replace => {
+            reply.updated = peers.iter().filter(|peer| exists(ab, &peer.id)).count();
+            reply.added = peers.len() - reply.updated;
+            reply.removed = ab.peers.len() - reply.updated;
+
+            ab.peers.clear();
+            ab.tags = import.tags;
+
+            for peer in peers {
+                // Ids were checked to be unique above.
+                let _ = ab_edit::add_peer(ab, peer);
+            }
+        },
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csv_import(data: &str) -> Ab {
+        let mut ab = Ab::default();
+        import(&mut ab, parse_csv(data).unwrap(), AbImportMode::Replace).unwrap();
+        ab
+    }
+
+    #[test]
+    fn csv_formulas_round_trip() {
+        let ab = csv_import("id,alias,tags,note\n1,=cmd|' /C calc'!A0,-x;y,@SUM(A1)\n2,'quoted,,plain\n3,''=x,,\n");
+        assert_eq!(ab.peers[1].alias, "'quoted");
+        assert_eq!(ab.peers[2].alias, "'=x");
+
+        let data = export_csv(&ab).unwrap();
+        assert!(data.contains(",'=cmd|' /C calc'!A0,"));
+        assert!(data.contains(",'-x;y,"));
+        assert!(data.contains(",'@SUM(A1)\n"));
+        assert!(data.contains(",''quoted,"));
+        assert!(data.contains(",''=x,"));
+
+        assert_eq!(csv_import(&data), ab);
+    }
+}
@@ -1,28 +1,106 @@
 use rocket::{
+    http::{Status, ContentType},
+    response::{self, Response, Responder},
+    form::FromFormField,
+    request::{Request, FromRequest, Outcome},
     serde::{Serialize, Deserialize},
 };
+use serde_json::{Map, Value};
+use std::time::{Duration, SystemTime};
 
 use crate::{
     tokens::Token,
 };
 
+/// The password step carries `username` and `password`; the second step carries `secret` from the first reply,
+/// and `tfaCode` or `verificationCode`.
 #[derive(Deserialize, Debug)]
 pub struct LoginRequest {
+    #[serde(default)]
     pub username: String,
+    #[serde(default)]
     pub password: String,
     pub id: String,
     pub uuid: String,
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    #[serde(rename = "tfaCode")]
+    pub tfa_code: Option<String>,
+    /// Code sent by email.
+    #[serde(default)]
+    #[serde(rename = "verificationCode")]
+    pub verification_code: Option<String>,
+}
+
+impl LoginRequest {
+    /// The code of the second step, whichever field the client put it in.
+    pub fn code(&self) -> &str {
+        [&self.tfa_code, &self.verification_code]
+            .into_iter()
+            .flatten()
+            .find(|code| !code.is_empty())
+            .map_or("", String::as_str)
+    }
 }
 
 #[derive(Serialize, Debug)]
 pub struct UserInfo {
     pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginReplyType {
+    AccessToken,
+    /// Ask the user for a TOTP code.
+    TfaCheck,
+    /// Ask the user for the code sent by email.
+    EmailCheck,
 }
 
 #[derive(Serialize, Debug)]
 pub struct LoginReply {
-    pub user: UserInfo,
-    pub access_token: Token,
+    #[serde(rename = "type")]
+    pub reply_type: LoginReplyType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<UserInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_token: Option<Token>,
+    /// Sent back with the code to complete the login.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl LoginReply {
+    pub fn access_token(user: UserInfo, access_token: Token) -> Self {
+        Self {
+            reply_type: LoginReplyType::AccessToken,
+            user: Some(user),
+            access_token: Some(access_token),
+            secret: None,
+        }
+    }
+
+    pub fn tfa_check(secret: String) -> Self {
+        Self {
+            reply_type: LoginReplyType::TfaCheck,
+            user: None,
+            access_token: None,
+            secret: Some(secret),
+        }
+    }
+
+    pub fn email_check(user: UserInfo, secret: String) -> Self {
+        Self {
+            reply_type: LoginReplyType::EmailCheck,
+            user: Some(user),
+            access_token: None,
+            secret: Some(secret),
+        }
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -63,26 +141,601 @@ pub struct AuditRequest {
     pub uuid: String,
 }
 
+/// `/api/audit/conn`: posted when a connection is opened (`new`), authorized (no action, `peer` set) and closed (`close`).
+#[derive(Deserialize, Debug)]
+pub struct ConnAuditRequest {
+    /// Id of the device reporting.
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub uuid: String,
+    #[serde(default)]
+    pub conn_id: i64,
+    #[serde(default)]
+    pub action: String,
+    #[serde(default)]
+    pub ip: String,
+    /// Id and name of the connecting device.
+    #[serde(default)]
+    pub peer: Option<(String, String)>,
+    #[serde(default)]
+    #[serde(rename = "type")]
+    pub conn_type: Option<i64>,
+}
+
+/// `/api/audit/file`: a file transfer.
+#[derive(Deserialize, Debug)]
+pub struct FileAuditRequest {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub uuid: String,
+    #[serde(default)]
+    #[serde(rename = "type")]
+    pub transfer_type: i64,
+    /// JSON document with the peer address and the transferred files; `peer_id`, `path` and `is_file` are
+    /// not stored on their own.
+    #[serde(default)]
+    pub info: String,
+}
+
+/// `/api/audit/alarm`: too many failed login attempts and the like.
+#[derive(Deserialize, Debug)]
+pub struct AlarmAuditRequest {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub uuid: String,
+    #[serde(default)]
+    pub typ: i64,
+    /// JSON document with the peer address and details.
+    #[serde(default)]
+    pub info: String,
+}
+
+pub const AUDIT_QUERY_DEFAULT_LIMIT: u32 = 100;
+pub const AUDIT_QUERY_MAX_LIMIT: u32 = 1000;
+
+#[derive(FromFormField, Debug, Clone, Copy, PartialEq, Default)]
+pub enum AuditFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// Query string of `GET /api/audit` and `GET /api/audit/sessions`. Times are seconds since the epoch.
+#[derive(Debug, Default)]
+pub struct AuditQuery {
+    /// `audit`, `conn`, `file` or `alarm`.
+    pub kind: Option<String>,
+    pub user: Option<String>,
+    pub peer_id: Option<String>,
+    pub uuid: Option<String>,
+    pub action: Option<String>,
+    pub ip: Option<String>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    /// `next_cursor` of the previous page.
+    pub cursor: Option<i64>,
+    pub limit: Option<u32>,
+    pub format: AuditFormat,
+}
+
+fn query_value<'r, T: FromFormField<'r>>(request: &'r Request<'_>, name: &str) -> Result<Option<T>, ()> {
+    request.query_value::<T>(name).transpose().map_err(|_| ())
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuditQuery {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let parse = || -> Result<Self, ()> {
+            Ok(Self {
+                kind: query_value(request, "kind")?,
+                user: query_value(request, "user")?,
+                peer_id: query_value(request, "peer_id")?,
+                uuid: query_value(request, "uuid")?,
+                action: query_value(request, "action")?,
+                ip: query_value(request, "ip")?,
+                from: query_value(request, "from")?,
+                to: query_value(request, "to")?,
+                cursor: query_value(request, "cursor")?,
+                limit: query_value(request, "limit")?,
+                format: query_value(request, "format")?.unwrap_or_default(),
+            })
+        };
+
+        match parse() {
+            Ok(query) => Outcome::Success(query),
+            Err(()) => Outcome::Failure((Status::BadRequest, ())),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuditPageReply<T> {
+    pub data: Vec<T>,
+    /// Pass as `cursor` to get the next page; null on the last page.
+    pub next_cursor: Option<i64>,
+}
+
+/// CSV flavour of [`AuditPageReply`], the cursor goes into the `X-Next-Cursor` header.
+#[derive(Debug)]
+pub struct AuditPageCsv {
+    pub data: String,
+    pub next_cursor: Option<i64>,
+}
+
+impl<'r> Responder<'r, 'static> for AuditPageCsv {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build_from(self.data.respond_to(request)?);
+        response.header(ContentType::CSV);
+
+        if let Some(next_cursor) = self.next_cursor {
+            response.raw_header("X-Next-Cursor", next_cursor.to_string());
+        }
+
+        response.ok()
+    }
+}
+
 // {
 //    peers: [{id: "abcd", username: "", hostname: "", platform: "", alias: "", tags: ["", "", ...]}, ...],
 //    tags: [],
 // }
 
-#[derive(Serialize, Debug)]
+/// A peer of an address book. Fields we don't know about are kept as they are.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct AbPeer {
     pub id: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub hostname: String,
+    #[serde(default)]
+    pub platform: String,
+    #[serde(default)]
+    pub alias: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub hash: String,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
 }
 
-#[derive(Serialize, Debug)]
+/// The address book, as it travels JSON-encoded in `data` of `/api/ab` and `/api/ab/get`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub struct Ab {
+    #[serde(default)]
     pub tags: Vec<String>,
+    #[serde(default)]
     pub peers: Vec<AbPeer>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
 }
 
+/// A stored version of an address book.
+#[derive(Serialize, Debug)]
+pub struct AbRevisionInfo {
+    pub revision_id: i64,
+    /// Seconds since the epoch.
+    pub created_at: u64,
+    pub peers: usize,
+    pub tags: usize,
+}
+
+/// Changes from one revision of an address book to another.
+#[derive(Serialize, Debug, Default)]
+pub struct AbDiff {
+    pub peers_added: Vec<AbPeer>,
+    pub peers_removed: Vec<AbPeer>,
+    pub peers_changed: Vec<AbPeerChange>,
+    pub tags_added: Vec<String>,
+    pub tags_removed: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AbPeerChange {
+    pub before: AbPeer,
+    pub after: AbPeer,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ErrorReply {
+    pub error: String,
+}
+
+/// Whether an `If-Match` or `If-None-Match` header value lists `etag`, or is `*`.
+pub fn etag_list_contains(list: &str, etag: &str) -> bool {
+    list
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/").trim_matches('"') == etag)
+}
+
+/// `If-Match`, `If-None-Match` and `If-Modified-Since` of a request.
+#[derive(Debug, Default)]
+pub struct Conditions {
+    pub if_match: Option<String>,
+    pub if_none_match: Option<String>,
+    /// Seconds since the epoch.
+    pub if_modified_since: Option<u64>,
+}
+
+impl Conditions {
+    /// Whether a resource with this `etag` and modification time is unchanged for the client.
+    pub fn not_modified(&self, etag: &str, updated_at: u64) -> bool {
+        // `If-None-Match` wins if both are present.
+        if let Some(if_none_match) = &self.if_none_match {
+            return etag_list_contains(if_none_match, etag);
+        }
+
+        // A resource without a modification time has no `Last-Modified` to compare with.
+        match self.if_modified_since {
+            Some(if_modified_since) if updated_at != 0 => updated_at <= if_modified_since,
+            _ => false,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Conditions {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+
+        // Unparsable dates are ignored, as RFC 7232 asks.
+        let if_modified_since = headers
+            .get_one("If-Modified-Since")
+            .and_then(|date| httpdate::parse_http_date(date).ok())
+            .and_then(|date| date.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|since| since.as_secs());
+
+        Outcome::Success(Self {
+            if_match: headers.get_one("If-Match").map(str::to_string),
+            if_none_match: headers.get_one("If-None-Match").map(str::to_string),
+            if_modified_since,
+        })
+    }
+}
+
+/// A response carrying `ETag` and `Last-Modified`, or an empty 304 Not Modified if `inner` is `None`.
+/// `Last-Modified` is left out for resources which were never modified, like a new address book.
+#[derive(Debug)]
+pub struct Conditional<R> {
+    pub inner: Option<R>,
+    pub etag: String,
+    /// Seconds since the epoch, 0 if unknown.
+    pub updated_at: u64,
+}
+
+impl<'r, R: Responder<'r, 'static>> Responder<'r, 'static> for Conditional<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = match self.inner {
+            Some(inner) => Response::build_from(inner.respond_to(request)?),
+            None => {
+                let mut response = Response::build();
+                response.status(Status::NotModified);
+                response
+            },
+        };
+
+        response.raw_header("ETag", format!("\"{}\"", self.etag));
+
+        if self.updated_at != 0 {
+            let updated_at = SystemTime::UNIX_EPOCH + Duration::from_secs(self.updated_at);
+            response.raw_header("Last-Modified", httpdate::fmt_http_date(updated_at));
+        }
+
+        response.ok()
+    }
+}
 
 #[derive(Serialize, Debug)]
 pub struct AbGetResponse {
     pub error: bool,
     pub updated_at: String,
     pub data: String,
-}
\ No newline at end of file
+}
+/// Access to an address book, numbered as RustDesk clients expect it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AbRule {
+    Read = 1,
+    ReadWrite = 2,
+    /// Owners of personal books.
+    Full = 3,
+}
+
+impl AbRule {
+    pub fn from_i64(rule: i64) -> Option<Self> {
+        match rule {
+            1 => Some(Self::Read),
+            2 => Some(Self::ReadWrite),
+            3 => Some(Self::Full),
+            _ => None,
+        }
+    }
+}
+
+pub const AB_PAGE_DEFAULT_SIZE: usize = 100;
+pub const AB_PAGE_MAX_SIZE: usize = 1000;
+
+/// `current` and `pageSize` query parameters of the shared address book API. Pages count from 1.
+#[derive(Debug)]
+pub struct Pagination {
+    pub current: usize,
+    pub page_size: usize,
+}
+
+impl Pagination {
+    pub fn page<T>(&self, items: Vec<T>) -> AbPageReply<T> {
+        let total = items.len();
+        let data = items
+            .into_iter()
+            .skip((self.current - 1).saturating_mul(self.page_size))
+            .take(self.page_size)
+            .collect();
+
+        AbPageReply { total, data }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Pagination {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let parse = || -> Result<Self, ()> {
+            Ok(Self {
+                current: query_value(request, "current")?.unwrap_or(1).max(1),
+                page_size: query_value(request, "pageSize")?.unwrap_or(AB_PAGE_DEFAULT_SIZE).clamp(1, AB_PAGE_MAX_SIZE),
+            })
+        };
+
+        match parse() {
+            Ok(pagination) => Outcome::Success(pagination),
+            Err(()) => Outcome::Failure((Status::BadRequest, ())),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct AbPageReply<T> {
+    /// Number of items on all pages.
+    pub total: usize,
+    pub data: Vec<T>,
+}
+
+/// A shared address book as listed by `/api/ab/shared/profiles`.
+#[derive(Serialize, Debug)]
+pub struct AbProfile {
+    pub guid: String,
+    pub name: String,
+    pub owner: String,
+    pub note: String,
+    pub rule: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AbTag {
+    pub name: String,
+    /// ARGB.
+    pub color: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AbSettingsReply {
+    /// Zero for no limit.
+    pub max_peer_one_ab: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AbPersonalReply {
+    pub guid: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AbTagRenameRequest {
+    pub old: String,
+    pub new: String,
+}
+
+#[derive(FromFormField, Debug, Clone, Copy, PartialEq, Default)]
+pub enum AbFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(FromFormField, Debug, Clone, Copy, PartialEq, Default)]
+pub enum AbImportMode {
+    /// Add new peers, update existing ones.
+    #[default]
+    Merge,
+    /// Drop peers which are not imported.
+    Replace,
+}
+
+/// Query string of `GET /api/ab/export` and `POST /api/ab/import`.
+#[derive(Debug, Default)]
+pub struct AbTransferQuery {
+    pub format: AbFormat,
+    pub mode: AbImportMode,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AbTransferQuery {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let parse = || -> Result<Self, ()> {
+            Ok(Self {
+                format: query_value(request, "format")?.unwrap_or_default(),
+                mode: query_value(request, "mode")?.unwrap_or_default(),
+            })
+        };
+
+        match parse() {
+            Ok(query) => Outcome::Success(query),
+            Err(()) => Outcome::Failure((Status::BadRequest, ())),
+        }
+    }
+}
+
+/// An exported address book, served as a file download.
+#[derive(Debug)]
+pub struct AbExport {
+    pub format: AbFormat,
+    pub data: String,
+}
+
+impl<'r> Responder<'r, 'static> for AbExport {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let (content_type, filename) = match self.format {
+            AbFormat::Json => (ContentType::JSON, "address_book.json"),
+            AbFormat::Csv => (ContentType::CSV, "address_book.csv"),
+        };
+
+        Response::build_from(self.data.respond_to(request)?)
+            .header(content_type)
+            .raw_header("Content-Disposition", format!("attachment; filename=\"{}\"", filename))
+            .ok()
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct AbImportReply {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AbImportRowError {
+    /// Counts from 1, zero for errors about the whole file.
+    pub row: usize,
+    pub error: String,
+}
+
+/// Nothing is imported if any row fails.
+#[derive(Serialize, Debug)]
+pub struct AbImportErrorReply {
+    pub error: String,
+    pub rows: Vec<AbImportRowError>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SessionRecord {
+    pub session_id: i64,
+    /// Address the login came from.
+    pub ip: String,
+    /// Login time, seconds since the epoch.
+    pub created_at: u64,
+    pub last_seen_at: u64,
+}
+
+/// Sessions of a user logged in from one device.
+#[derive(Serialize, Debug)]
+pub struct DeviceSessions {
+    pub device_id: String,
+    pub device_uuid: String,
+    pub sessions: Vec<SessionRecord>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UserSessionsReply {
+    /// The session of this request.
+    pub current_session_id: i64,
+    pub devices: Vec<DeviceSessions>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RevokeSessionsReply {
+    pub revoked: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TfaSetupReply {
+    /// Base32-encoded, for entering by hand.
+    pub secret: String,
+    /// `otpauth://` URI, for a QR code.
+    pub uri: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TfaCodeRequest {
+    pub code: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TfaEnableReply {
+    /// One-time codes to log in without the authenticator. They are not shown again.
+    pub recovery_codes: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::asynchronous::Client;
+
+    fn conditions(if_none_match: Option<&str>, if_modified_since: Option<u64>) -> Conditions {
+        Conditions { if_match: None, if_none_match: if_none_match.map(str::to_string), if_modified_since }
+    }
+
+    #[test]
+    fn address_book_keeps_unknown_fields() {
+        let data = r#"{"tags":["a","b"],"peers":[{"id":"1","alias":"one","tags":["a"],"forceAlwaysRelay":"true"},{"id":"2"}],"tag_colors":"{\"a\":1}"}"#;
+        let ab: Ab = serde_json::from_str(data).unwrap();
+
+        assert_eq!(ab.tags, ["a", "b"]);
+        assert_eq!((ab.peers[0].id.as_str(), ab.peers[0].alias.as_str()), ("1", "one"));
+        assert_eq!(ab.peers[0].other["forceAlwaysRelay"], "true");
+        assert_eq!((ab.peers[1].alias.as_str(), ab.peers[1].tags.len()), ("", 0));
+        assert_eq!(ab.other["tag_colors"], r#"{"a":1}"#);
+
+        let reparsed: Ab = serde_json::from_str(&serde_json::to_string(&ab).unwrap()).unwrap();
+        assert_eq!(reparsed, ab);
+
+        // A peer needs an id.
+        assert!(serde_json::from_str::<Ab>(r#"{"peers":[{"alias":"one"}]}"#).is_err());
+    }
+
+    #[test]
+    fn not_modified() {
+        assert!(conditions(Some("\"a\", \"b\""), None).not_modified("b", 100));
+        assert!(conditions(Some("*"), None).not_modified("b", 100));
+        assert!(!conditions(Some("\"a\""), Some(200)).not_modified("b", 100));
+
+        assert!(conditions(None, Some(100)).not_modified("b", 100));
+        assert!(!conditions(None, Some(99)).not_modified("b", 100));
+        assert!(!conditions(None, Some(100)).not_modified("b", 0));
+        assert!(!conditions(None, None).not_modified("b", 100));
+    }
+
+    fn respond(inner: Option<&'static str>, updated_at: u64, request: &Request<'_>) -> Response<'static> {
+        Conditional { inner, etag: "abc".to_string(), updated_at }.respond_to(request).unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn conditional_headers() {
+        let client = Client::untracked(rocket::build()).await.unwrap();
+        let request = client.get("/");
+
+        let mut response = respond(Some("body"), 784111777, request.inner());
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("ETag"), Some("\"abc\""));
+        assert_eq!(response.headers().get_one("Last-Modified"), Some("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert_eq!(response.body_mut().to_string().await.unwrap(), "body");
+
+        let response = respond(None, 784111777, request.inner());
+        assert_eq!(response.status(), Status::NotModified);
+        assert_eq!(response.headers().get_one("ETag"), Some("\"abc\""));
+
+        let response = respond(Some("body"), 0, request.inner());
+        assert_eq!(response.headers().get_one("ETag"), Some("\"abc\""));
+        assert_eq!(response.headers().get_one("Last-Modified"), None);
+    }
+}
@@ -0,0 +1,336 @@
+use std::{borrow::Cow, net::IpAddr};
+use rocket::serde::{Serialize, Serializer};
+use crate::{
+    api::{AuditRequest, ConnAuditRequest, FileAuditRequest, AlarmAuditRequest},
+    state::UserId,
+};
+
+/// Which endpoint an audit event was posted to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditKind {
+    /// `/api/audit`, older clients.
+    Audit,
+    /// `/api/audit/conn`
+    Conn,
+    /// `/api/audit/file`
+    File,
+    /// `/api/audit/alarm`
+    Alarm,
+}
+
+impl AuditKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Audit => "audit",
+            Self::Conn => "conn",
+            Self::File => "file",
+            Self::Alarm => "alarm",
+        }
+    }
+}
+
+/// What a connection audit tells about the connection it belongs to.
+#[derive(Debug, Clone)]
+pub enum ConnSessionEvent {
+    Open {
+        ip: String,
+    },
+    Authorized {
+        peer_id: String,
+        peer_name: String,
+        conn_type: i64,
+    },
+    Close,
+}
+
+/// An audit request as it is stored in the `audit_events` table.
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub kind: AuditKind,
+    pub created_at: u64,
+    pub source_ip: IpAddr,
+    pub user_id: Option<UserId>,
+    pub conn_id: i64,
+    pub action: String,
+    pub peer_id: String,
+    pub peer_ip: String,
+    pub uuid: String,
+    pub payload: String,
+    /// Set for connection audits, correlated into `conn_sessions` when stored.
+    pub conn_session: Option<ConnSessionEvent>,
+}
+
+impl AuditEvent {
+    pub fn new(created_at: u64, source_ip: IpAddr, user_id: Option<UserId>, request: AuditRequest, payload: String) -> Self {
+        Self {
+            kind: AuditKind::Audit,
+            created_at,
+            source_ip,
+            user_id,
+            conn_id: request.id_ as i64,
+            action: request.action,
+            peer_id: request.id,
+            peer_ip: request.ip,
+            uuid: request.uuid,
+            payload,
+            conn_session: None,
+        }
+    }
+
+    pub fn from_conn(created_at: u64, source_ip: IpAddr, user_id: Option<UserId>, request: ConnAuditRequest, payload: String) -> Self {
+        // Clients post the peer after authorization without an action.
+        let conn_session = match (request.action.as_str(), request.peer) {
+            ("new", _) => Some(ConnSessionEvent::Open { ip: request.ip.clone() }),
+            ("close", _) => Some(ConnSessionEvent::Close),
+            (_, Some((peer_id, peer_name))) => Some(ConnSessionEvent::Authorized {
+                peer_id,
+                peer_name,
+                conn_type: request.conn_type.unwrap_or_default(),
+            }),
+            _ => None,
+        };
+
+        let action = match (request.action.is_empty(), &conn_session) {
+            (true, Some(ConnSessionEvent::Authorized { .. })) => "authorized".to_string(),
+            _ => request.action,
+        };
+
+        Self {
+            kind: AuditKind::Conn,
+            created_at,
+            source_ip,
+            user_id,
+            conn_id: request.conn_id,
+            action,
+            peer_id: request.id,
+            peer_ip: request.ip,
+            uuid: request.uuid,
+            payload,
+            conn_session,
+        }
+    }
+
+    pub fn from_file(created_at: u64, source_ip: IpAddr, user_id: Option<UserId>, request: FileAuditRequest, payload: String) -> Self {
+        let action = match request.transfer_type {
+            0 => "remote_send".to_string(),
+            1 => "remote_receive".to_string(),
+            other => format!("file_{}", other),
+        };
+
+        Self {
+            kind: AuditKind::File,
+            created_at,
+            source_ip,
+            user_id,
+            conn_id: 0,
+            action,
+            peer_id: request.id,
+            peer_ip: info_ip(&request.info),
+            uuid: request.uuid,
+            payload,
+            conn_session: None,
+        }
+    }
+
+    pub fn from_alarm(created_at: u64, source_ip: IpAddr, user_id: Option<UserId>, request: AlarmAuditRequest, payload: String) -> Self {
+        let action = match request.typ {
+            0 => "ip_whitelist".to_string(),
+            1 => "exceed_thirty_attempts".to_string(),
+            2 => "six_attempts_within_one_minute".to_string(),
+            3 => "exceed_ipv6_prefix_attempts".to_string(),
+            other => format!("alarm_{}", other),
+        };
+
+        Self {
+            kind: AuditKind::Alarm,
+            created_at,
+            source_ip,
+            user_id,
+            conn_id: 0,
+            action,
+            peer_id: request.id,
+            peer_ip: info_ip(&request.info),
+            uuid: request.uuid,
+            payload,
+            conn_session: None,
+        }
+    }
+}
+
+/// File and alarm audits carry the peer address inside `info`, itself a JSON document.
+fn info_ip(info: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(info)
+        .ok()
+        .and_then(|info| info.get("ip").and_then(|ip| ip.as_str()).map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Conditions for looking up stored audit events. Unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub kind: Option<String>,
+    pub username: Option<String>,
+    pub peer_id: Option<String>,
+    pub uuid: Option<String>,
+    pub action: Option<String>,
+    /// Matches either the address the event came from or the peer address.
+    pub ip: Option<String>,
+    /// Inclusive lower bound of `created_at`.
+    pub from: Option<u64>,
+    /// Exclusive upper bound of `created_at`.
+    pub to: Option<u64>,
+    /// Only events older than this event id.
+    pub cursor: Option<i64>,
+    pub limit: u32,
+}
+
+/// A stored audit event, as returned to administrators.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct AuditEventRecord {
+    pub event_id: i64,
+    pub kind: String,
+    pub created_at: i64,
+    pub source_ip: String,
+    pub user_id: Option<UserId>,
+    pub username: Option<String>,
+    pub conn_id: i64,
+    pub action: String,
+    pub peer_id: String,
+    pub peer_ip: String,
+    pub uuid: String,
+    /// The request body as received, served back as JSON.
+    #[serde(serialize_with = "serialize_payload")]
+    pub payload: String,
+}
+
+/// A connection, from the `new` connection audit to the `close` one.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ConnSessionRecord {
+    pub conn_session_id: i64,
+    /// The device which was connected to.
+    pub device_id: String,
+    pub device_uuid: String,
+    pub conn_id: i64,
+    /// The device which connected, known once it is authorized.
+    pub peer_id: String,
+    pub peer_name: String,
+    pub conn_type: Option<i64>,
+    pub ip: String,
+    pub opened_at: i64,
+    pub closed_at: Option<i64>,
+    /// Seconds, once closed.
+    pub duration: Option<i64>,
+}
+
+/// Rows served by the audit query endpoints, as JSON or CSV.
+pub trait AuditRecord: Serialize + std::fmt::Debug {
+    const CSV_HEADER: &'static [&'static str];
+
+    fn to_csv_record(&self) -> Vec<String>;
+
+    /// Cursor to pass to get the rows after this one.
+    fn cursor(&self) -> i64;
+}
+
+impl AuditRecord for AuditEventRecord {
+    const CSV_HEADER: &'static [&'static str] = &[
+        "event_id", "kind", "created_at", "source_ip", "user_id", "username", "conn_id",
+        "action", "peer_id", "peer_ip", "uuid", "payload",
+    ];
+
+    fn to_csv_record(&self) -> Vec<String> {
+        vec![
+            self.event_id.to_string(),
+            self.kind.clone(),
+            self.created_at.to_string(),
+            self.source_ip.clone(),
+            self.user_id.map(|user_id| user_id.to_string()).unwrap_or_default(),
+            self.username.clone().unwrap_or_default(),
+            self.conn_id.to_string(),
+            self.action.clone(),
+            self.peer_id.clone(),
+            self.peer_ip.clone(),
+            self.uuid.clone(),
+            self.payload.clone(),
+        ]
+    }
+
+    fn cursor(&self) -> i64 {
+        self.event_id
+    }
+}
+
+impl AuditRecord for ConnSessionRecord {
+    const CSV_HEADER: &'static [&'static str] = &[
+        "conn_session_id", "device_id", "device_uuid", "conn_id", "peer_id", "peer_name",
+        "conn_type", "ip", "opened_at", "closed_at", "duration",
+    ];
+
+    fn to_csv_record(&self) -> Vec<String> {
+        let optional = |value: Option<i64>| value.map(|value| value.to_string()).unwrap_or_default();
+
+        vec![
+            self.conn_session_id.to_string(),
+            self.device_id.clone(),
+            self.device_uuid.clone(),
+            self.conn_id.to_string(),
+            self.peer_id.clone(),
+            self.peer_name.clone(),
+            optional(self.conn_type),
+            self.ip.clone(),
+            self.opened_at.to_string(),
+            optional(self.closed_at),
+            optional(self.duration),
+        ]
+    }
+
+    fn cursor(&self) -> i64 {
+        self.conn_session_id
+    }
+}
+
+fn serialize_payload<S: Serializer>(payload: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    match serde_json::from_str::<serde_json::Value>(payload) {
+        Ok(value) => value.serialize(serializer),
+        Err(_) => serializer.serialize_str(payload),
+    }
+}
+
+/// A CSV cell which spreadsheets won't take for a formula: a leading `=`, `+`, `-`, `@`, tab or carriage return
+/// gets a `'` in front. So does a leading `'`, to tell escaped values from values which start with one.
+pub fn csv_cell(value: &str) -> Cow<'_, str> {
+    match value.chars().next() {
+        Some('=' | '+' | '-' | '@' | '\t' | '\r' | '\'') => Cow::Owned(format!("'{}", value)),
+        _ => Cow::Borrowed(value),
+    }
+}
+
+/// Records as CSV with a header line, payloads kept verbatim except for `csv_cell`.
+pub fn to_csv<T: AuditRecord>(records: &[T]) -> Option<String> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+
+    writer.write_record(T::CSV_HEADER).ok()?;
+    for record in records {
+        let cells = record.to_csv_record();
+        writer.write_record(cells.iter().map(|cell| csv_cell(cell).into_owned())).ok()?;
+    }
+
+    String::from_utf8(writer.into_inner().ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_cell_escapes_formulas() {
+        assert_eq!(csv_cell("=HYPERLINK(\"http://x\")"), "'=HYPERLINK(\"http://x\")");
+        assert_eq!(csv_cell("+1"), "'+1");
+        assert_eq!(csv_cell("-1"), "'-1");
+        assert_eq!(csv_cell("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_cell("\tx"), "'\tx");
+        assert_eq!(csv_cell("'=x"), "''=x");
+        assert_eq!(csv_cell("192.0.2.1"), "192.0.2.1");
+        assert_eq!(csv_cell(""), "");
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+};
+use rocket::{
+    serde::Deserialize,
+    figment::{Figment, providers::{Toml, Format}},
+};
+use crate::{
+    database::Database,
+    state::UserId,
+    passwords::{PasswordCheck, verify_password, hash_password},
+    config::{AuthConfig, AuthBackendKind},
+};
+
+/// Where users and their passwords come from.
+///
+/// Users are owned by the first backend which knows them: its answer is final, other backends are not asked.
+/// Each user has a row in `users` recording the owning backend, so another backend can't take the user over.
+#[rocket::async_trait]
+pub trait AuthBackend: Send + Sync {
+    /// Stored in `users.auth_backend` for the users of the backend, as in `auth.backends`.
+    fn name(&self) -> &'static str;
+
+    /// Check the credentials of a user; `None` if the backend doesn't know the user.
+    async fn verify(&self, username: &str, password: &str) -> Option<AuthCheck>;
+
+    /// Name clients show instead of the username, if the backend has one.
+    async fn display_name(&self, username: &str) -> Option<String>;
+}
+
+/// What a backend says about the credentials of a user it knows.
+pub enum AuthCheck {
+    /// Wrong password, or the user is disabled.
+    Denied,
+    /// Valid credentials, with the id of the user if the backend keeps users in `users` itself.
+    Valid(Option<UserId>),
+}
+
+/// The backends of `auth.backends`, in order.
+pub struct AuthChain {
+    db: Database,
+    backends: Vec<Box<dyn AuthBackend>>,
+}
+
+impl AuthChain {
+    pub fn new(config: &AuthConfig, db: &Database) -> Result<Self, String> {
+        let backends = config.backends
+            .iter()
+            .map(|kind| -> Result<Box<dyn AuthBackend>, String> {
+                Ok(match kind {
+                    AuthBackendKind::Database => Box::new(DatabaseAuthBackend { db: db.clone() }),
+                    AuthBackendKind::File => Box::new(FileAuthBackend::load(&config.users_file)?),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if backends.is_empty() {
+            return Err("No authentication backends configured in auth.backends".to_string());
+        }
+
+        Ok(Self { db: db.clone(), backends })
+    }
+
+    /// The id of the user, if the user is known, active, and has this password. Users of backends other than
+    /// the database get a row in `users` on first login, and can be disabled there too.
+    pub async fn verify(&self, username: &str, password: &str) -> Option<UserId> {
+        for backend in self.backends.iter() {
+            match backend.verify(username, password).await {
+                None => continue,
+                Some(AuthCheck::Denied) => return None,
+                Some(AuthCheck::Valid(Some(user_id))) => return Some(user_id),
+                Some(AuthCheck::Valid(None)) => return self.external_user(username, backend.name()).await,
+            }
+        }
+
+        None
+    }
+
+    /// The id of a user of `backend`, `None` if the user is disabled or belongs to another backend.
+    async fn external_user(&self, username: &str, backend: &str) -> Option<UserId> {
+        let (user_id, db_user_info) = self.db.find_or_create_external_user(username, backend).await?;
+
+        if db_user_info.auth_backend != backend {
+            tracing::warn!(
+                "Refusing login of {} through the {} backend, the user belongs to the {} backend",
+                username, backend, db_user_info.auth_backend,
+            );
+            return None;
+        }
+
+        db_user_info.active.then_some(user_id)
+    }
+
+    /// The display name from the backend owning the user.
+    pub async fn display_name(&self, username: &str) -> Option<String> {
+        let (_, db_user_info) = self.db.find_user_by_name(username).await.1?;
+
+        self.backends
+            .iter()
+            .find(|backend| backend.name() == db_user_info.auth_backend)?
+            .display_name(username)
+            .await
+    }
+}
+
+/// Users with a password in the `passwords` table.
+pub struct DatabaseAuthBackend {
+    db: Database,
+}
+
+#[rocket::async_trait]
+impl AuthBackend for DatabaseAuthBackend {
+    fn name(&self) -> &'static str {
+        "database"
+    }
+
+    async fn verify(&self, username: &str, password: &str) -> Option<AuthCheck> {
+        let (conn, user_id, active, db_password_info) = match self.db.find_user_password(username).await {
+            (conn, Some((user_id, active, db_password_info))) => (conn, user_id, active, db_password_info),
+            _ => return None,
+        };
+
+        // The password is checked first, so whether a user is disabled can't be told without it.
+        let check = match verify_password(&db_password_info.password, password) {
+            PasswordCheck::Invalid => AuthCheck::Denied,
+            _ if !active => AuthCheck::Denied,
+            PasswordCheck::Valid => AuthCheck::Valid(Some(user_id)),
+            PasswordCheck::ValidNeedsRehash => {
+                tracing::info!("Rehashing stored password of user {}", user_id);
+                if let Some(password) = hash_password(password) {
+                    self.db.set_user_password(conn, user_id, &password).await;
+                }
+                AuthCheck::Valid(Some(user_id))
+            },
+        };
+
+        Some(check)
+    }
+
+    async fn display_name(&self, _username: &str) -> Option<String> {
+        None
+    }
+}
+
+/// Users listed in a TOML file, read at startup:
+///
+/// ```toml
+/// [alice]
+/// password = "$argon2id$v=19$..."
+/// display_name = "Alice"
+/// active = true
+/// ```
+///
+/// Passwords are Argon2 or bcrypt hashes; plaintext works too, but isn't recommended.
+pub struct FileAuthBackend {
+    users: HashMap<String, FileUser>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FileUser {
+    password: String,
+    #[serde(default)]
+    display_name: Option<String>,
+    #[serde(default = "default_active")]
+    active: bool,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+impl FileAuthBackend {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read users file {}: {}", path.display(), e))?;
+
+        let users = Figment::from(Toml::string(&data))
+            .extract()
+            .map_err(|e| format!("Malformed users file {}: {}", path.display(), e))?;
+
+        Ok(Self { users })
+    }
+}
+
+#[rocket::async_trait]
+impl AuthBackend for FileAuthBackend {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    async fn verify(&self, username: &str, password: &str) -> Option<AuthCheck> {
+        let user = self.users.get(username)?;
+
+        if verify_password(&user.password, password) != PasswordCheck::Invalid && user.active {
+            Some(AuthCheck::Valid(None))
+        } else {
+            Some(AuthCheck::Denied)
+        }
+    }
+
+    async fn display_name(&self, username: &str) -> Option<String> {
+        self.users.get(username)?.display_name.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USERS_FILE: &str = r#"
+        [carol]
+        password = "carol-password"
+        display_name = "Carol"
+
+        [dave]
+        password = "dave-password"
+        active = false
+
+        [alice]
+        password = "file-password"
+    "#;
+
+    async fn test_chain(name: &str, backends: Vec<AuthBackendKind>) -> (AuthChain, Database) {
+        let db = Database::open_temporary(name).await;
+        db.create_user("alice", &hash_password("alice-password").unwrap(), true).await.unwrap();
+        db.create_user("bob", "bob-password", false).await.unwrap();
+
+        let users_file = std::env::temp_dir().join(format!("rustdesk-api-test-{}-{}.toml", std::process::id(), name));
+        std::fs::write(&users_file, USERS_FILE).unwrap();
+
+        let chain = AuthChain::new(&AuthConfig { backends, users_file }, &db).unwrap();
+        (chain, db)
+    }
+
+    #[rocket::async_test]
+    async fn database_backend() {
+        let (chain, db) = test_chain("auth_database", vec![AuthBackendKind::Database]).await;
+        let alice = db.find_user_by_name("alice").await.1.unwrap().0;
+
+        assert_eq!(chain.verify("alice", "alice-password").await, Some(alice));
+        assert_eq!(chain.verify("alice", "wrong").await, None);
+        assert_eq!(chain.verify("nobody", "alice-password").await, None);
+        // Disabled.
+        assert_eq!(chain.verify("bob", "bob-password").await, None);
+        // Not asked.
+        assert_eq!(chain.verify("carol", "carol-password").await, None);
+    }
+
+    #[rocket::async_test]
+    async fn database_backend_rehashes_plaintext() {
+        let (chain, db) = test_chain("auth_rehash", vec![AuthBackendKind::Database]).await;
+        let bob = db.find_user_by_name("bob").await.1.unwrap().0;
+        db.set_user_active(bob, true).await.unwrap();
+
+        assert_eq!(chain.verify("bob", "bob-password").await, Some(bob));
+
+        let (_, stored) = db.find_user_password("bob").await;
+        let stored = stored.unwrap().2.password;
+        assert!(stored.starts_with("$argon2id$"));
+        assert_eq!(chain.verify("bob", "bob-password").await, Some(bob));
+    }
+
+    #[rocket::async_test]
+    async fn file_backend_after_database() {
+        let (chain, db) = test_chain("auth_file", vec![AuthBackendKind::Database, AuthBackendKind::File]).await;
+
+        // Created on first login, owned by the file backend.
+        let carol = chain.verify("carol", "carol-password").await.unwrap();
+        let (user_id, db_user_info) = db.find_user_by_name("carol").await.1.unwrap();
+        assert_eq!((user_id, db_user_info.auth_backend.as_str()), (carol, "file"));
+        assert_eq!(chain.verify("carol", "carol-password").await, Some(carol));
+        assert_eq!(chain.verify("carol", "wrong").await, None);
+        assert_eq!(chain.display_name("carol").await.as_deref(), Some("Carol"));
+
+        // Disabled in the file, and in the database.
+        assert_eq!(chain.verify("dave", "dave-password").await, None);
+        db.set_user_active(carol, false).await.unwrap();
+        assert_eq!(chain.verify("carol", "carol-password").await, None);
+
+        // The database knows alice first.
+        assert!(chain.verify("alice", "alice-password").await.is_some());
+        assert_eq!(chain.verify("alice", "file-password").await, None);
+        assert_eq!(chain.display_name("alice").await, None);
+    }
+
+    #[rocket::async_test]
+    async fn file_backend_cant_take_over_database_users() {
+        let (chain, _db) = test_chain("auth_takeover", vec![AuthBackendKind::File, AuthBackendKind::Database]).await;
+
+        assert_eq!(chain.verify("alice", "file-password").await, None);
+        assert_eq!(chain.verify("alice", "alice-password").await, None);
+        assert!(chain.verify("carol", "carol-password").await.is_some());
+    }
+}
@@ -1,3 +1,4 @@
+use std::sync::Arc;
 use rocket::{
     http::{
         Status,
@@ -56,7 +57,15 @@ impl<'r> FromRequest<'r> for BearerToken {
 pub struct AuthenticatedUser {
     pub session_id: SessionId,
     pub user_id: UserId,
-    pub access_token: Token,
+    pub device_id: String,
+    pub device_uuid: String,
+}
+
+impl AuthenticatedUser {
+    /// Whether the request came from the device which logged in. Only some requests tell the device.
+    pub fn is_device(&self, device_id: &str, device_uuid: &str) -> bool {
+        self.device_id == device_id && self.device_uuid == device_uuid
+    }
 }
 
 #[rocket::async_trait]
@@ -72,7 +81,7 @@ impl<'r> FromRequest<'r> for AuthenticatedUser {
             .ok_or(Outcome::Failure((Status::Unauthorized, ())))
         );
 
-        let state = request.guard::<&State<ApiState>>().await.succeeded().unwrap();
+        let state = request.guard::<&State<Arc<ApiState>>>().await.succeeded().unwrap();
             
         let access_token_info = unwrap_or_return!(
             state
@@ -84,9 +93,57 @@ impl<'r> FromRequest<'r> for AuthenticatedUser {
         let authenticated_user = AuthenticatedUser {
             session_id: access_token_info.session_id,
             user_id: access_token_info.user_id,
-            access_token
+            device_id: access_token_info.device_id,
+            device_uuid: access_token_info.device_uuid,
         };
 
         Outcome::Success(authenticated_user)
     }
-}
\ No newline at end of file
+}
+/// An authenticated user with administrator rights.
+#[derive(Debug)]
+pub struct AdminUser {
+    pub user: AuthenticatedUser,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminUser {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user = unwrap_or_return!(
+            request
+            .guard::<AuthenticatedUser>()
+            .await
+            .success_or(Outcome::Failure((Status::Unauthorized, ())))
+        );
+
+        let state = request.guard::<&State<Arc<ApiState>>>().await.succeeded().unwrap();
+
+        if !state.is_user_admin(user.user_id).await {
+            return Outcome::Failure((Status::Forbidden, ()));
+        }
+
+        Outcome::Success(Self { user })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_binding() {
+        let user = AuthenticatedUser {
+            session_id: 1,
+            user_id: 1,
+            device_id: "123456789".to_string(),
+            device_uuid: "uuid".to_string(),
+        };
+
+        assert!(user.is_device("123456789", "uuid"));
+        assert!(!user.is_device("123456789", "other"));
+        assert!(!user.is_device("987654321", "uuid"));
+        assert!(!user.is_device("", ""));
+    }
+}
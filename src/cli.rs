@@ -0,0 +1,582 @@
+use std::{
+    io::BufRead,
+    path::PathBuf,
+};
+use clap::{Parser, Subcommand, Args, ArgEnum};
+use rocket::figment::Figment;
+use crate::{
+    database::{Database, DatabaseLock},
+    passwords::hash_password,
+    totp,
+    AddressBook,
+    state::{UserId, BookId, secs_from_epoch},
+    api::{AbRule, AbFormat, AbImportMode},
+    config::{self, ApiConfig, DEFAULT_CONFIG_FILENAME},
+};
+
+#[derive(Parser, Debug)]
+#[clap(version, about = "RustDesk API server")]
+pub struct Cli {
+    /// Path to the TOML config file.
+    #[clap(long, global = true, env = "RUSTDESK_API_CONFIG", default_value = DEFAULT_CONFIG_FILENAME)]
+    pub config: PathBuf,
+
+    /// Path to the sqlite database, overrides the config.
+    #[clap(long, global = true)]
+    pub database: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manage users.
+    #[clap(subcommand)]
+    User(UserCommand),
+    /// Manage shared address books.
+    #[clap(subcommand)]
+    Ab(AbCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UserCommand {
+    /// Create a new user.
+    Add {
+        username: String,
+        /// Create the user disabled.
+        #[clap(long)]
+        disabled: bool,
+        #[clap(flatten)]
+        password: PasswordArgs,
+    },
+    /// Change password of a user.
+    Passwd {
+        username: String,
+        #[clap(flatten)]
+        password: PasswordArgs,
+    },
+    /// Forbid a user to log in and revoke all the user's access tokens. Running servers drop the tokens on their
+    /// next maintenance run, see `maintenance_interval`.
+    Disable {
+        username: String,
+    },
+    /// Allow a disabled user to log in again.
+    Enable {
+        username: String,
+    },
+    /// Grant a user administrator rights, e.g. querying the audit log.
+    Admin {
+        username: String,
+        /// Take the rights away instead.
+        #[clap(long)]
+        revoke: bool,
+    },
+    /// Enable two-factor authentication for a user, printing the secret and the recovery codes.
+    Tfa {
+        username: String,
+        /// Turn it off instead, e.g. for a user who lost the authenticator and the recovery codes.
+        #[clap(long)]
+        disable: bool,
+    },
+    /// Set the email address of a user, where verification codes are sent. Without an address, remove it.
+    Email {
+        username: String,
+        email: Option<String>,
+    },
+    /// Revoke all access tokens of a user. Running servers drop them on their next maintenance run.
+    Revoke {
+        username: String,
+    },
+    /// List all users.
+    List,
+    /// Delete a user together with the address book.
+    Delete {
+        username: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AbCommand {
+    /// Create a shared address book.
+    Create {
+        name: String,
+        /// Description shown to clients.
+        #[clap(long, default_value = "")]
+        note: String,
+    },
+    /// List shared address books.
+    List,
+    /// Delete a shared address book with its revisions.
+    Delete {
+        name: String,
+    },
+    /// Give a user access to a shared address book, read-only unless `--write` is given.
+    Grant {
+        name: String,
+        username: String,
+        /// Allow the user to change the book.
+        #[clap(long)]
+        write: bool,
+    },
+    /// Take a user's access to a shared address book away.
+    Revoke {
+        name: String,
+        username: String,
+    },
+    /// Write the personal address book of a user to stdout or a file.
+    Export {
+        username: String,
+        #[clap(long, arg_enum, default_value = "json")]
+        format: FileFormat,
+        /// Write to this file instead of stdout.
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+    /// Import peers into the personal address book of a user. Refuses to run while a server uses the database,
+    /// it would overwrite the import with the book it caches; import through `/api/ab/import` then.
+    Import {
+        username: String,
+        file: PathBuf,
+        /// Defaults to the file extension.
+        #[clap(long, arg_enum)]
+        format: Option<FileFormat>,
+        /// Drop peers which are not in the file, instead of merging.
+        #[clap(long)]
+        replace: bool,
+    },
+}
+
+#[derive(ArgEnum, Debug, Clone, Copy)]
+pub enum FileFormat {
+    Json,
+    Csv,
+}
+
+impl From<FileFormat> for AbFormat {
+    fn from(format: FileFormat) -> Self {
+        match format {
+            FileFormat::Json => AbFormat::Json,
+            FileFormat::Csv => AbFormat::Csv,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct PasswordArgs {
+    /// Read the password from the first line of stdin instead of prompting.
+    #[clap(long)]
+    password_stdin: bool,
+}
+
+type CliResult = Result<(), String>;
+
+impl Cli {
+    pub fn figment(&self) -> Figment {
+        let figment = config::figment(&self.config);
+
+        match &self.database {
+            Some(database) => figment.merge(("database", database)),
+            None => figment,
+        }
+    }
+}
+
+pub async fn run(config: &ApiConfig, command: Command) -> CliResult {
+    let db = Database::open( &config.database ).await?;
+
+    match command {
+        Command::User(command) => run_user_command(&db, config, command).await,
+        Command::Ab(command) => run_ab_command(&db, config, command).await,
+    }
+}
+
+async fn run_user_command(db: &Database, config: &ApiConfig, command: UserCommand) -> CliResult {
+    // Running servers see revoked sessions on their next maintenance run.
+    let revoke_delay = config.maintenance_interval.max(1);
+
+    match command {
+        UserCommand::Add { username, disabled, password } => {
+            if find_user(db, &username).await.is_ok() {
+                return Err(format!("User {} already exists", username));
+            }
+
+            let password = read_password(&password)?;
+            let user_id = db
+                .create_user(&username, &password, !disabled)
+                .await
+                .ok_or_else(|| format!("Failed to create user {}", username))?;
+
+            println!("Created user {} with id {}", username, user_id);
+        },
+        UserCommand::Passwd { username, password } => {
+            let (conn, user_id) = match db.find_user_by_name(&username).await {
+                (conn, Some((user_id, db_user_info))) if db_user_info.auth_backend == "database" => (conn, user_id),
+                (_, Some((_, db_user_info))) => {
+                    return Err(format!("User {} belongs to the {} authentication backend", username, db_user_info.auth_backend));
+                },
+                _ => return Err(format!("User {} not found", username)),
+            };
+
+            let password = read_password(&password)?;
+
+            db.set_user_password(conn, user_id, &password)
+                .await
+                .1
+                .ok_or_else(|| format!("Failed to change password of user {}", username))?;
+
+            println!("Password of user {} changed", username);
+        },
+        UserCommand::Disable { username } => {
+            set_active(db, &username, false).await?;
+            let revoked = revoke_sessions(db, &username).await?;
+            println!("User {} disabled, {} sessions revoked", username, revoked);
+            println!("A running server accepts them for up to {} more seconds, until its next maintenance run", revoke_delay);
+        },
+        UserCommand::Enable { username } => {
+            set_active(db, &username, true).await?;
+            println!("User {} enabled", username);
+        },
+        UserCommand::Admin { username, revoke } => {
+            let user_id = find_user(db, &username).await?;
+
+            db.set_user_admin(user_id, !revoke)
+                .await
+                .ok_or_else(|| format!("Failed to update user {}", username))?;
+
+            if revoke {
+                println!("User {} is no longer an administrator", username);
+            } else {
+                println!("User {} is now an administrator", username);
+            }
+        },
+        UserCommand::Tfa { username, disable: true } => {
+            let user_id = find_user(db, &username).await?;
+
+            db.disable_user_totp(user_id)
+                .await
+                .ok_or_else(|| format!("Failed to update user {}", username))?;
+
+            println!("Two-factor authentication of user {} disabled", username);
+        },
+        UserCommand::Tfa { username, disable: false } => {
+            let user_id = find_user(db, &username).await?;
+
+            let db_totp = db
+                .get_user_totp(user_id)
+                .await
+                .ok_or_else(|| format!("Failed to load user {}", username))?;
+
+            if db_totp.enabled {
+                return Err(format!("User {} has two-factor authentication enabled already", username));
+            }
+
+            let secret = totp::new_secret();
+            let recovery_codes = totp::new_recovery_codes();
+            let code_hashes: Vec<String> = recovery_codes.iter().map(|code| totp::hash_recovery_code(code)).collect();
+
+            db.set_user_totp_secret(user_id, &secret)
+                .await
+                .ok_or_else(|| format!("Failed to update user {}", username))?;
+            db.enable_user_totp(user_id, 0, &code_hashes)
+                .await
+                .ok_or_else(|| format!("Failed to update user {}", username))?;
+
+            println!("Two-factor authentication of user {} enabled", username);
+            println!("Secret: {}", secret);
+            println!("URI: {}", totp::otpauth_uri(&username, &secret));
+            println!("Recovery codes:");
+            for code in recovery_codes {
+                println!("  {}", code);
+            }
+        },
+        UserCommand::Email { username, email } => {
+            let user_id = find_user(db, &username).await?;
+
+            if let Some(email) = &email {
+                email
+                    .parse::<lettre::Address>()
+                    .map_err(|e| format!("Invalid email address {}: {}", email, e))?;
+            }
+
+            db.set_user_email(user_id, email.as_deref())
+                .await
+                .ok_or_else(|| format!("Failed to update user {}", username))?;
+
+            match email {
+                Some(email) => println!("Email address of user {} set to {}", username, email),
+                None => println!("Email address of user {} removed", username),
+            }
+        },
+        UserCommand::Revoke { username } => {
+            let revoked = revoke_sessions(db, &username).await?;
+            println!("{} sessions of user {} revoked", revoked, username);
+            println!("A running server accepts them for up to {} more seconds, until its next maintenance run", revoke_delay);
+        },
+        UserCommand::List => {
+            let users = db
+                .list_users()
+                .await
+                .ok_or_else(|| "Failed to list users".to_string())?;
+
+            println!("{:>8}  {:<8}  USERNAME", "ID", "STATUS");
+            for user in users {
+                let status = match (user.active, user.admin) {
+                    (false, _) => "disabled",
+                    (true, false) => "active",
+                    (true, true) => "admin",
+                };
+                println!("{:>8}  {:<8}  {}", user.user_id, status, user.username);
+            }
+        },
+        UserCommand::Delete { username } => {
+            let user_id = find_user(db, &username).await?;
+
+            db.delete_user(user_id)
+                .await
+                .ok_or_else(|| format!("Failed to delete user {}", username))?;
+
+            println!("User {} deleted", username);
+        },
+    }
+
+    Ok(())
+}
+
+/// Running servers pick up changed permissions on the next request; deleted books are no longer written.
+async fn run_ab_command(db: &Database, config: &ApiConfig, command: AbCommand) -> CliResult {
+    match command {
+        AbCommand::Create { name, note } => {
+            if find_book(db, &name).await.is_ok() {
+                return Err(format!("Address book {} already exists", name));
+            }
+
+            let book_id = db
+                .create_shared_book(&name, &note)
+                .await
+                .ok_or_else(|| format!("Failed to create address book {}", name))?;
+
+            println!("Created address book {} with id {}", name, book_id);
+        },
+        AbCommand::List => {
+            let books = db
+                .list_shared_books(None)
+                .await
+                .ok_or_else(|| "Failed to list address books".to_string())?;
+
+            println!("{:>8}  {:<36}  {:>5}  NAME", "ID", "GUID", "USERS");
+            for book in books {
+                println!("{:>8}  {:<36}  {:>5}  {}", book.book_id, book.guid, book.users, book.name);
+            }
+        },
+        AbCommand::Delete { name } => {
+            let book_id = find_book(db, &name).await?;
+
+            db.delete_shared_book(book_id)
+                .await
+                .ok_or_else(|| format!("Failed to delete address book {}", name))?;
+
+            println!("Address book {} deleted", name);
+        },
+        AbCommand::Grant { name, username, write } => {
+            let book_id = find_book(db, &name).await?;
+            let user_id = find_user(db, &username).await?;
+
+            let rule = if write { AbRule::ReadWrite } else { AbRule::Read };
+
+            db.set_book_permission(book_id, user_id, rule)
+                .await
+                .ok_or_else(|| format!("Failed to grant access to address book {}", name))?;
+
+            println!("User {} has {} access to address book {}", username, if write { "read-write" } else { "read" }, name);
+        },
+        AbCommand::Revoke { name, username } => {
+            let book_id = find_book(db, &name).await?;
+            let user_id = find_user(db, &username).await?;
+
+            let revoked = db
+                .remove_book_permission(book_id, user_id)
+                .await
+                .ok_or_else(|| format!("Failed to revoke access to address book {}", name))?;
+
+            if !revoked {
+                return Err(format!("User {} has no access to address book {}", username, name));
+            }
+
+            println!("User {} has no access to address book {} anymore", username, name);
+        },
+        AbCommand::Export { username, format, output } => {
+            let (_, address_book) = load_personal_book(db, &username).await?;
+
+            let data = address_book
+                .export(format.into())
+                .ok_or_else(|| format!("Failed to export address book of user {}", username))?;
+
+            match output {
+                Some(output) => std::fs::write(&output, data)
+                    .map_err(|e| format!("Failed to write {}: {}", output.display(), e))?,
+                None => print!("{}", data),
+            }
+        },
+        AbCommand::Import { username, file, format, replace } => {
+            let format = match format {
+                Some(format) => format,
+                None if file.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("csv")) => FileFormat::Csv,
+                None => FileFormat::Json,
+            };
+            let mode = if replace { AbImportMode::Replace } else { AbImportMode::Merge };
+
+            let _db_lock = DatabaseLock::acquire(&config.database)
+                .map_err(|e| format!("{}; stop it first, or import through /api/ab/import", e))?;
+
+            let data = std::fs::read_to_string(&file)
+                .map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+
+            let (book_id, mut address_book) = load_personal_book(db, &username).await?;
+
+            let reply = match address_book.import(&data, format.into(), mode) {
+                Ok(reply) => reply,
+                Err(rows) => {
+                    for row in rows {
+                        eprintln!("Row {}: {}", row.row, row.error);
+                    }
+                    return Err("Nothing imported, some rows are invalid".to_string());
+                },
+            };
+
+            db.update_address_books(vec![(book_id, address_book, secs_from_epoch())])
+                .await
+                .ok_or_else(|| format!("Failed to store address book of user {}", username))?;
+
+            println!("Imported into address book of user {}: {} added, {} updated, {} removed", username, reply.added, reply.updated, reply.removed);
+        },
+    }
+
+    Ok(())
+}
+
+async fn load_personal_book(db: &Database, username: &str) -> Result<(BookId, AddressBook), String> {
+    let user_id = find_user(db, username).await?;
+
+    let (book_id, _) = db
+        .get_personal_book(user_id)
+        .await
+        .ok_or_else(|| format!("Failed to load address book of user {}", username))?;
+
+    let (address_book, _) = db
+        .get_address_book(book_id)
+        .await
+        .ok_or_else(|| format!("Failed to load address book of user {}", username))?;
+
+    Ok((book_id, address_book))
+}
+
+async fn find_book(db: &Database, name: &str) -> Result<BookId, String> {
+    db.find_shared_book_by_name(name)
+        .await
+        .ok_or_else(|| format!("Address book {} not found", name))
+}
+
+async fn find_user(db: &Database, username: &str) -> Result<UserId, String> {
+    match db.find_user_by_name(username).await {
+        (_, Some((user_id, _))) => Ok(user_id),
+        _ => Err(format!("User {} not found", username)),
+    }
+}
+
+async fn set_active(db: &Database, username: &str, active: bool) -> CliResult {
+    let user_id = find_user(db, username).await?;
+
+    db.set_user_active(user_id, active)
+        .await
+        .ok_or_else(|| format!("Failed to update user {}", username))
+}
+
+/// Running servers drop revoked sessions on their next maintenance run.
+async fn revoke_sessions(db: &Database, username: &str) -> Result<u64, String> {
+    let user_id = find_user(db, username).await?;
+
+    db.delete_user_sessions(user_id)
+        .await
+        .ok_or_else(|| format!("Failed to revoke sessions of user {}", username))
+}
+
+/// Read a new password and return its hash, ready to be stored.
+fn read_password(args: &PasswordArgs) -> Result<String, String> {
+    let password = if args.password_stdin {
+        let mut line = String::new();
+        std::io::stdin()
+            .lock()
+            .read_line(&mut line)
+            .map_err(|e| format!("Failed to read password: {}", e))?;
+        line.trim_end_matches(&['\r', '\n'][..]).to_string()
+    } else {
+        let password = rpassword::prompt_password("Password: ")
+            .map_err(|e| format!("Failed to read password: {}", e))?;
+        let confirmation = rpassword::prompt_password("Repeat password: ")
+            .map_err(|e| format!("Failed to read password: {}", e))?;
+
+        if password != confirmation {
+            return Err("Passwords do not match".to_string());
+        }
+
+        password
+    };
+
+    if password.is_empty() {
+        return Err("Password must not be empty".to_string());
+    }
+
+    hash_password(&password).ok_or_else(|| "Failed to hash password".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use crate::tokens::Token;
+
+    async fn user_command(db: &Database, args: &[&str]) -> CliResult {
+        let cli = Cli::try_parse_from([&["rustdesk-api-server", "user"], args].concat()).unwrap();
+        match cli.command {
+            Some(Command::User(command)) => run_user_command(db, &ApiConfig::default(), command).await,
+            command => panic!("Not a user command: {:?}", command),
+        }
+    }
+
+    async fn is_active(db: &Database, username: &str) -> bool {
+        db.find_user_by_name(username).await.1.unwrap().1.active
+    }
+
+    #[rocket::async_test]
+    async fn user_commands() {
+        let db = Database::open_temporary("cli_user").await;
+        let user_id = db.create_user("alice", "password", true).await.unwrap();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        db.insert_session(Token::new_random().hash(), user_id, "1", "uuid", ip, secs_from_epoch()).await.unwrap();
+
+        user_command(&db, &["disable", "alice"]).await.unwrap();
+        assert!(!is_active(&db, "alice").await);
+
+        // Enabling the user again doesn't bring the revoked sessions back.
+        user_command(&db, &["enable", "alice"]).await.unwrap();
+        assert!(is_active(&db, "alice").await);
+        assert!(db.load_sessions().await.unwrap().is_empty());
+
+        user_command(&db, &["admin", "alice"]).await.unwrap();
+        assert!(db.is_user_admin(user_id).await);
+        user_command(&db, &["admin", "alice", "--revoke"]).await.unwrap();
+        assert!(!db.is_user_admin(user_id).await);
+
+        user_command(&db, &["email", "alice", "alice@example.com"]).await.unwrap();
+        assert_eq!(db.get_user_email(user_id).await.unwrap().as_deref(), Some("alice@example.com"));
+        assert!(user_command(&db, &["email", "alice", "not an address"]).await.is_err());
+        user_command(&db, &["email", "alice"]).await.unwrap();
+        assert_eq!(db.get_user_email(user_id).await.unwrap(), None);
+
+        assert_eq!(user_command(&db, &["enable", "bob"]).await.unwrap_err(), "User bob not found");
+
+        user_command(&db, &["delete", "alice"]).await.unwrap();
+        assert!(db.find_user_by_name("alice").await.1.is_none());
+    }
+}
@@ -0,0 +1,166 @@
+use std::net::{IpAddr, SocketAddr};
+use ipnet::IpNet;
+use rocket::{
+    http::Status,
+    request::{Request, FromRequest, Outcome},
+    State,
+};
+use crate::unwrap_or_return;
+
+const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+
+/// Reverse proxies whose `X-Forwarded-*` headers are believed.
+#[derive(Debug, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+    tls: bool,
+}
+
+impl TrustedProxies {
+    /// Parse proxy addresses, either single IPs or CIDR networks. `tls` tells whether we serve HTTPS ourselves.
+    pub fn new<S: AsRef<str>>(proxies: &[S], tls: bool) -> Result<Self, String> {
+        let networks = proxies
+            .iter()
+            .map(|proxy| {
+                let proxy = proxy.as_ref();
+                proxy.parse::<IpNet>()
+                    .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("Invalid trusted proxy address: {}", proxy))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { networks, tls })
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    /// The rightmost address of `X-Forwarded-For` entries which is not one of our proxies, or the leftmost one if
+    /// they all are. Entries left of one which doesn't parse could be made up by anyone, so then it's `remote_ip`.
+    fn client_ip<'a, I>(&self, remote_ip: IpAddr, forwarded: I) -> IpAddr
+    where
+        I: DoubleEndedIterator<Item = &'a str>,
+    {
+        let mut leftmost = remote_ip;
+
+        for entry in forwarded.rev() {
+            let ip = match parse_forwarded_ip(entry) {
+                Some(ip) => ip,
+                None => return remote_ip,
+            };
+
+            if !self.is_trusted(&ip) {
+                return ip;
+            }
+            leftmost = ip;
+        }
+
+        leftmost
+    }
+}
+
+/// An `X-Forwarded-For` entry: an IP, maybe with a port, IPv6 ones maybe in brackets.
+fn parse_forwarded_ip(entry: &str) -> Option<IpAddr> {
+    let entry = entry.trim();
+
+    entry.parse::<IpAddr>().ok()
+        .or_else(|| entry.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| entry.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+/// Address of the client, as reported by trusted proxies if the request came through one.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr {
+    pub ip: IpAddr,
+    /// Whether the client connected over HTTPS, as `X-Forwarded-Proto` tells; only shown in logs.
+    pub https: bool,
+}
+
+impl std::fmt::Display for ClientAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.ip, if self.https { "https" } else { "http" })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientAddr {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let remote_ip = unwrap_or_return!(
+            request
+            .remote()
+            .map(|remote| remote.ip())
+            .ok_or(Outcome::Failure((Status::BadRequest, ())))
+        );
+
+        let proxies = request.guard::<&State<TrustedProxies>>().await.succeeded().unwrap();
+
+        if !proxies.is_trusted(&remote_ip) {
+            return Outcome::Success(Self { ip: remote_ip, https: proxies.tls });
+        }
+
+        let forwarded: Vec<&str> = request
+            .headers()
+            .get(X_FORWARDED_FOR)
+            .flat_map(|value| value.split(','))
+            .collect();
+
+        let ip = proxies.client_ip(remote_ip, forwarded.into_iter());
+
+        let https = request
+            .headers()
+            .get_one(X_FORWARDED_PROTO)
+            .map(|proto| proto.trim().eq_ignore_ascii_case("https"))
+            .unwrap_or(proxies.tls);
+
+        Outcome::Success(Self { ip, https })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn client_ip(forwarded: &str) -> IpAddr {
+        let proxies = TrustedProxies::new(&["10.0.0.0/8", "fd00::1"], false).unwrap();
+        proxies.client_ip(ip("10.0.0.1"), forwarded.split(','))
+    }
+
+    #[test]
+    fn rightmost_untrusted() {
+        assert_eq!(client_ip("203.0.113.7"), ip("203.0.113.7"));
+        assert_eq!(client_ip("198.51.100.1, 203.0.113.7, 10.0.0.2"), ip("203.0.113.7"));
+        assert_eq!(client_ip("203.0.113.7, 10.0.0.3, 10.0.0.2"), ip("203.0.113.7"));
+        assert_eq!(client_ip("2001:db8::7, fd00::1"), ip("2001:db8::7"));
+    }
+
+    #[test]
+    fn all_trusted() {
+        assert_eq!(client_ip("10.0.0.3, 10.0.0.2"), ip("10.0.0.3"));
+    }
+
+    #[test]
+    fn ports() {
+        assert_eq!(client_ip("198.51.100.1, 203.0.113.7:4711, 10.0.0.2:80"), ip("203.0.113.7"));
+        assert_eq!(client_ip("198.51.100.1, [2001:db8::7]:4711, [fd00::1]"), ip("2001:db8::7"));
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(client_ip("203.0.113.7, garbage, 10.0.0.2"), ip("10.0.0.1"));
+        assert_eq!(client_ip("203.0.113.7, 10.0.0.2, unknown"), ip("10.0.0.1"));
+        assert_eq!(client_ip(""), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn malformed_left_of_client() {
+        assert_eq!(client_ip("garbage, 203.0.113.7, 10.0.0.2"), ip("203.0.113.7"));
+    }
+}
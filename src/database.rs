@@ -1,10 +1,14 @@
-use std::path::Path;
-use sqlx::{QueryBuilder, sqlite::{Sqlite, SqlitePool, SqliteConnectOptions, SqliteJournalMode}, pool::PoolConnection};
+use std::{path::Path, net::IpAddr, fs::{File, TryLockError}};
+use sqlx::{QueryBuilder, Connection, FromRow, Transaction, sqlite::{Sqlite, SqlitePool, SqliteConnectOptions, SqliteJournalMode}, pool::PoolConnection};
 use crate::{
     AddressBook,
-    state::{UserId}
+    api::{Ab, AbPeer, AbRevisionInfo, AbRule},
+    tokens::TokenHash,
+    state::{UserId, SessionId, BookId},
+    audit_log::{AuditEvent, AuditEventRecord, AuditFilter, ConnSessionEvent, ConnSessionRecord},
 };
 
+#[derive(Clone)]
 pub struct Database {
     pool: SqlitePool,
 }
@@ -13,14 +17,289 @@ pub struct DatabaseConnection {
     conn: PoolConnection<Sqlite>
 }
 
+/// A lock on `<database>.lock`, held by a running server as long as it runs. Commands which write what the server
+/// caches take it too, so they don't run while the server would overwrite their changes.
+pub struct DatabaseLock {
+    _file: File,
+}
+
+impl DatabaseLock {
+    pub fn acquire<P: AsRef<Path>>(db_filename: P) -> Result<Self, String> {
+        let mut path = db_filename.as_ref().as_os_str().to_owned();
+        path.push(".lock");
+        let path = Path::new(&path);
+
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(format!("Database {} is in use by a running server", db_filename.as_ref().display())),
+            Err(TryLockError::Error(e)) => Err(format!("Failed to lock {}: {}", path.display(), e)),
+        }
+    }
+}
+
 pub struct DatabaseUserInfo {
     pub active: bool,
+    /// Name of the authentication backend owning the user.
+    pub auth_backend: String,
 }
 
 pub struct DatabaseUserPasswordInfo {
     pub password: String,
 }
 
+pub struct DatabaseSessionInfo {
+    pub session_id: SessionId,
+    pub token_hash: TokenHash,
+    pub user_id: UserId,
+    pub username: String,
+    pub device_id: String,
+    pub device_uuid: String,
+    /// Address the login came from.
+    pub ip: String,
+    pub created_at: u64,
+    pub last_seen_at: u64,
+}
+
+/// A shared address book. `rule` is the access of the user the books were listed for, if any.
+pub struct DatabaseBookRecord {
+    pub book_id: BookId,
+    pub guid: String,
+    pub name: String,
+    pub note: String,
+    pub rule: Option<AbRule>,
+    /// Number of users with access, when listed without a user.
+    pub users: i64,
+}
+
+pub struct DatabaseUserTotp {
+    pub secret: Option<String>,
+    pub enabled: bool,
+    /// Time step of the last accepted code.
+    pub last_step: u64,
+}
+
+pub struct DatabaseUserRecord {
+    pub user_id: UserId,
+    pub username: String,
+    pub active: bool,
+    pub admin: bool,
+}
+
+/// Schema changes on top of the tables created by `Database::init_db`, applied in order, once.
+/// Only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    r#"ALTER TABLE "users" ADD COLUMN "admin" BOOLEAN NOT NULL DEFAULT FALSE"#,
+    r#"ALTER TABLE "audit_events" ADD COLUMN "kind" TEXT NOT NULL DEFAULT 'audit'"#,
+    // Split the JSON address books into `ab_peers` and `ab_tags`, leaving only unknown fields in `address_books`.
+    r#"
+        INSERT INTO ab_tags (user_id, position, tag)
+        SELECT address_books.user_id, tags.key, tags.value
+        FROM address_books, json_each(address_books.ab, '$.tags') AS tags
+        WHERE json_valid(address_books.ab);
+
+        INSERT INTO ab_peers (user_id, position, peer_id, username, hostname, platform, alias, tags, hash, other)
+        SELECT
+            address_books.user_id,
+            peers.key,
+            COALESCE(json_extract(peers.value, '$.id'), ''),
+            COALESCE(json_extract(peers.value, '$.username'), ''),
+            COALESCE(json_extract(peers.value, '$.hostname'), ''),
+            COALESCE(json_extract(peers.value, '$.platform'), ''),
+            COALESCE(json_extract(peers.value, '$.alias'), ''),
+            COALESCE(json_extract(peers.value, '$.tags'), '[]'),
+            COALESCE(json_extract(peers.value, '$.hash'), ''),
+            json_remove(peers.value, '$.id', '$.username', '$.hostname', '$.platform', '$.alias', '$.tags', '$.hash')
+        FROM address_books, json_each(address_books.ab, '$.peers') AS peers
+        WHERE json_valid(address_books.ab) AND peers.type = 'object';
+
+        UPDATE address_books
+        SET ab = json_remove(ab, '$.tags', '$.peers')
+        WHERE json_valid(ab);
+    "#,
+    r#"
+        ALTER TABLE "address_books" ADD COLUMN "updated_at" INTEGER NOT NULL DEFAULT 0;
+
+        UPDATE address_books SET updated_at = CAST(strftime('%s', 'now') AS INTEGER);
+    "#,
+    // Key books by their own id instead of the owner's, so books can be shared. Personal books have an owner,
+    // shared ones are accessible through `ab_permissions`. Index names are kept for `Database::init_db`.
+    r#"
+        ALTER TABLE "address_books" RENAME TO "address_books_old";
+        ALTER TABLE "ab_peers" RENAME TO "ab_peers_old";
+        ALTER TABLE "ab_tags" RENAME TO "ab_tags_old";
+        ALTER TABLE "address_book_revisions" RENAME TO "address_book_revisions_old";
+
+        CREATE TABLE "address_books" (
+            "book_id"	INTEGER NOT NULL,
+            "guid"	TEXT NOT NULL,
+            "owner_id"	INTEGER,
+            "name"	TEXT NOT NULL,
+            "note"	TEXT NOT NULL DEFAULT '',
+            "ab"	TEXT NOT NULL,
+            "updated_at"	INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY("owner_id") REFERENCES "users"("user_id"),
+            PRIMARY KEY("book_id" AUTOINCREMENT)
+        );
+
+        CREATE TABLE "ab_peers" (
+            "book_id"	INTEGER NOT NULL,
+            "position"	INTEGER NOT NULL,
+            "peer_id"	TEXT NOT NULL,
+            "username"	TEXT NOT NULL,
+            "hostname"	TEXT NOT NULL,
+            "platform"	TEXT NOT NULL,
+            "alias"	TEXT NOT NULL,
+            "tags"	TEXT NOT NULL,
+            "hash"	TEXT NOT NULL,
+            "other"	TEXT NOT NULL,
+            FOREIGN KEY("book_id") REFERENCES "address_books"("book_id"),
+            PRIMARY KEY("book_id", "position")
+        );
+
+        CREATE TABLE "ab_tags" (
+            "book_id"	INTEGER NOT NULL,
+            "position"	INTEGER NOT NULL,
+            "tag"	TEXT NOT NULL,
+            FOREIGN KEY("book_id") REFERENCES "address_books"("book_id"),
+            PRIMARY KEY("book_id", "position")
+        );
+
+        CREATE TABLE "address_book_revisions" (
+            "revision_id"	INTEGER NOT NULL,
+            "book_id"	INTEGER NOT NULL,
+            "created_at"	INTEGER NOT NULL,
+            "ab"	TEXT NOT NULL,
+            FOREIGN KEY("book_id") REFERENCES "address_books"("book_id"),
+            PRIMARY KEY("revision_id" AUTOINCREMENT)
+        );
+
+        CREATE TABLE "ab_permissions" (
+            "book_id"	INTEGER NOT NULL,
+            "user_id"	INTEGER NOT NULL,
+            "rule"	INTEGER NOT NULL,
+            FOREIGN KEY("book_id") REFERENCES "address_books"("book_id"),
+            FOREIGN KEY("user_id") REFERENCES "users"("user_id"),
+            PRIMARY KEY("book_id", "user_id")
+        );
+
+        INSERT INTO address_books (guid, owner_id, name, ab, updated_at)
+        SELECT
+            lower(hex(randomblob(4)) || '-' || hex(randomblob(2)) || '-' || hex(randomblob(2)) || '-' || hex(randomblob(2)) || '-' || hex(randomblob(6))),
+            user_id,
+            'My address book',
+            ab,
+            updated_at
+        FROM address_books_old;
+
+        INSERT INTO ab_peers (book_id, position, peer_id, username, hostname, platform, alias, tags, hash, other)
+        SELECT address_books.book_id, position, peer_id, username, hostname, platform, alias, tags, hash, other
+        FROM ab_peers_old JOIN address_books ON address_books.owner_id = ab_peers_old.user_id;
+
+        INSERT INTO ab_tags (book_id, position, tag)
+        SELECT address_books.book_id, position, tag
+        FROM ab_tags_old JOIN address_books ON address_books.owner_id = ab_tags_old.user_id;
+
+        INSERT INTO address_book_revisions (revision_id, book_id, created_at, ab)
+        SELECT revision_id, address_books.book_id, created_at, address_book_revisions_old.ab
+        FROM address_book_revisions_old JOIN address_books ON address_books.owner_id = address_book_revisions_old.user_id;
+
+        DROP TABLE "ab_peers_old";
+        DROP TABLE "ab_tags_old";
+        DROP TABLE "address_book_revisions_old";
+        DROP TABLE "address_books_old";
+
+        CREATE UNIQUE INDEX "index_address_books_id" ON "address_books" (
+            "owner_id"
+        );
+
+        CREATE UNIQUE INDEX "index_address_books_guid" ON "address_books" (
+            "guid"
+        );
+
+        CREATE UNIQUE INDEX "index_address_books_shared_name" ON "address_books" (
+            "name"
+        ) WHERE "owner_id" IS NULL;
+
+        CREATE INDEX "index_ab_peers_peer_id" ON "ab_peers" (
+            "peer_id"
+        );
+
+        CREATE INDEX "index_ab_tags_tag" ON "ab_tags" (
+            "tag"
+        );
+
+        CREATE INDEX "index_address_book_revisions_user_id" ON "address_book_revisions" (
+            "book_id"
+        );
+
+        CREATE INDEX "index_ab_permissions_user_id" ON "ab_permissions" (
+            "user_id"
+        );
+    "#,
+    r#"ALTER TABLE "sessions" ADD COLUMN "ip" TEXT NOT NULL DEFAULT ''"#,
+    // TOTP two-factor authentication. The secret is set on enrolment and enabled once a code confirms it.
+    r#"
+        ALTER TABLE "users" ADD COLUMN "totp_secret" TEXT;
+        ALTER TABLE "users" ADD COLUMN "totp_enabled" BOOLEAN NOT NULL DEFAULT FALSE;
+        ALTER TABLE "users" ADD COLUMN "totp_last_step" INTEGER NOT NULL DEFAULT 0;
+
+        CREATE TABLE "recovery_codes" (
+            "user_id"	INTEGER NOT NULL,
+            "code_hash"	TEXT NOT NULL,
+            FOREIGN KEY("user_id") REFERENCES "users"("user_id"),
+            PRIMARY KEY("user_id", "code_hash")
+        );
+    "#,
+    // Email addresses for verification codes, and the devices each user logged in from. Devices with
+    // sessions are known already.
+    r#"
+        ALTER TABLE "users" ADD COLUMN "email" TEXT;
+
+        CREATE TABLE "known_devices" (
+            "user_id"	INTEGER NOT NULL,
+            "device_id"	TEXT NOT NULL,
+            "device_uuid"	TEXT NOT NULL,
+            "first_login_at"	INTEGER NOT NULL,
+            FOREIGN KEY("user_id") REFERENCES "users"("user_id"),
+            PRIMARY KEY("user_id", "device_id", "device_uuid")
+        );
+
+        INSERT INTO "known_devices"
+        SELECT
+            "user_id", "device_id", "device_uuid", MIN("created_at")
+        FROM
+            "sessions"
+        GROUP BY
+            "user_id", "device_id", "device_uuid";
+    "#,
+    // The authentication backend owning each user. Rows without a password were created for users of the file
+    // backend. Usernames become unique, see `check_unique_usernames`.
+    r#"
+        ALTER TABLE "users" ADD COLUMN "auth_backend" TEXT NOT NULL DEFAULT 'database';
+
+        UPDATE "users" SET "auth_backend" = 'file' WHERE "user_id" NOT IN (SELECT "user_id" FROM "passwords");
+
+        DROP INDEX "index_users_username";
+        CREATE UNIQUE INDEX "index_users_username" ON "users" (
+            "username"
+        );
+    "#,
+];
+
+/// The migration adding `users.auth_backend` and making usernames unique.
+const AUTH_BACKEND_MIGRATION: usize = 9;
+
+/// Rows per INSERT statement into `ab_peers`, which has 10 columns.
+const AB_PEERS_PER_INSERT: usize = 50;
+/// Rows per INSERT statement into `ab_tags`.
+const AB_TAGS_PER_INSERT: usize = 200;
+
+/// Rows per INSERT statement, keeps the number of bound parameters well below SQLite limits.
+const AUDIT_EVENTS_PER_INSERT: usize = 50;
+
 macro_rules! unwrap_or_return_tuple {
     ($first:expr, $opt:expr) => {
         match $opt {
@@ -31,7 +310,7 @@ macro_rules! unwrap_or_return_tuple {
 }
 
 impl Database {
-    pub async fn open<P: AsRef<Path>>( db_filename: P ) -> Self {
+    pub async fn open<P: AsRef<Path>>( db_filename: P ) -> Result<Self, String> {
         let db_opts = SqliteConnectOptions::new()
             .filename(db_filename.as_ref())
             .journal_mode(SqliteJournalMode::Wal)
@@ -40,10 +319,11 @@ impl Database {
         let pool = SqlitePool::connect_with(db_opts).await.unwrap();
     
         Self::init_db(&pool).await;
+        Self::migrate_db(&pool, MIGRATIONS.len()).await?;
 
-        Self {
+        Ok(Self {
             pool
-        }
+        })
     }
 
     async fn init_db(pool: &SqlitePool) {
@@ -86,19 +366,212 @@ impl Database {
             CREATE UNIQUE INDEX IF NOT EXISTS "index_address_books_id" ON "address_books" (
                 "user_id"
             );
+
+            CREATE TABLE IF NOT EXISTS "ab_peers" (
+                "user_id"	INTEGER NOT NULL,
+                "position"	INTEGER NOT NULL,
+                "peer_id"	TEXT NOT NULL,
+                "username"	TEXT NOT NULL,
+                "hostname"	TEXT NOT NULL,
+                "platform"	TEXT NOT NULL,
+                "alias"	TEXT NOT NULL,
+                "tags"	TEXT NOT NULL,
+                "hash"	TEXT NOT NULL,
+                "other"	TEXT NOT NULL,
+                FOREIGN KEY("user_id") REFERENCES "users"("user_id"),
+                PRIMARY KEY("user_id", "position")
+            );
+
+            CREATE INDEX IF NOT EXISTS "index_ab_peers_peer_id" ON "ab_peers" (
+                "peer_id"
+            );
+
+            CREATE TABLE IF NOT EXISTS "ab_tags" (
+                "user_id"	INTEGER NOT NULL,
+                "position"	INTEGER NOT NULL,
+                "tag"	TEXT NOT NULL,
+                FOREIGN KEY("user_id") REFERENCES "users"("user_id"),
+                PRIMARY KEY("user_id", "position")
+            );
+
+            CREATE INDEX IF NOT EXISTS "index_ab_tags_tag" ON "ab_tags" (
+                "tag"
+            );
+
+            CREATE TABLE IF NOT EXISTS "address_book_revisions" (
+                "revision_id"	INTEGER NOT NULL,
+                "user_id"	INTEGER NOT NULL,
+                "created_at"	INTEGER NOT NULL,
+                "ab"	TEXT NOT NULL,
+                FOREIGN KEY("user_id") REFERENCES "users"("user_id"),
+                PRIMARY KEY("revision_id" AUTOINCREMENT)
+            );
+
+            CREATE INDEX IF NOT EXISTS "index_address_book_revisions_user_id" ON "address_book_revisions" (
+                "user_id"
+            );
+
+            CREATE TABLE IF NOT EXISTS "sessions" (
+                "session_id"	INTEGER NOT NULL,
+                "token_hash"	TEXT NOT NULL,
+                "user_id"	INTEGER NOT NULL,
+                "device_id"	TEXT NOT NULL,
+                "device_uuid"	TEXT NOT NULL,
+                "created_at"	INTEGER NOT NULL,
+                "last_seen_at"	INTEGER NOT NULL,
+                FOREIGN KEY("user_id") REFERENCES "users"("user_id"),
+                PRIMARY KEY("session_id" AUTOINCREMENT)
+            );
+
+            CREATE UNIQUE INDEX IF NOT EXISTS "index_sessions_token_hash" ON "sessions" (
+                "token_hash"
+            );
+
+            CREATE INDEX IF NOT EXISTS "index_sessions_user_id" ON "sessions" (
+                "user_id"
+            );
+
+            CREATE TABLE IF NOT EXISTS "audit_events" (
+                "event_id"	INTEGER NOT NULL,
+                "created_at"	INTEGER NOT NULL,
+                "source_ip"	TEXT NOT NULL,
+                "user_id"	INTEGER,
+                "conn_id"	INTEGER NOT NULL,
+                "action"	TEXT NOT NULL,
+                "peer_id"	TEXT NOT NULL,
+                "peer_ip"	TEXT NOT NULL,
+                "uuid"	TEXT NOT NULL,
+                "payload"	TEXT NOT NULL,
+                PRIMARY KEY("event_id" AUTOINCREMENT)
+            );
+
+            CREATE INDEX IF NOT EXISTS "index_audit_events_created_at" ON "audit_events" (
+                "created_at"
+            );
+
+            CREATE INDEX IF NOT EXISTS "index_audit_events_peer_id" ON "audit_events" (
+                "peer_id"
+            );
+
+            CREATE TABLE IF NOT EXISTS "conn_sessions" (
+                "conn_session_id"	INTEGER NOT NULL,
+                "device_id"	TEXT NOT NULL,
+                "device_uuid"	TEXT NOT NULL,
+                "conn_id"	INTEGER NOT NULL,
+                "peer_id"	TEXT NOT NULL DEFAULT '',
+                "peer_name"	TEXT NOT NULL DEFAULT '',
+                "conn_type"	INTEGER,
+                "ip"	TEXT NOT NULL,
+                "opened_at"	INTEGER NOT NULL,
+                "closed_at"	INTEGER,
+                "duration"	INTEGER,
+                PRIMARY KEY("conn_session_id" AUTOINCREMENT)
+            );
+
+            CREATE INDEX IF NOT EXISTS "index_conn_sessions_conn_id" ON "conn_sessions" (
+                "device_id",
+                "device_uuid",
+                "conn_id"
+            );
         "#)
         .execute(&mut conn)
         .await
         .unwrap();
     }
 
+    /// Apply `MIGRATIONS` the database doesn't have yet, up to `target` of them. `PRAGMA user_version` holds how
+    /// many are applied.
+    async fn migrate_db(pool: &SqlitePool, target: usize) -> Result<(), String> {
+        let mut conn = pool.acquire().await.unwrap();
+
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().take(target).skip(version as usize) {
+            if index + 1 == AUTH_BACKEND_MIGRATION {
+                Self::check_unique_usernames(&mut conn).await?;
+            }
+
+            tracing::info!("Applying database migration {}", index + 1);
+
+            let mut tx = conn.begin().await.unwrap();
+
+            sqlx::query(migration)
+                .execute(&mut tx)
+                .await
+                .unwrap();
+
+            sqlx::query(&format!("PRAGMA user_version = {}", index + 1))
+                .execute(&mut tx)
+                .await
+                .unwrap();
+
+            tx.commit().await.unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Before `AUTH_BACKEND_MIGRATION`: usernames must be unique already, only concurrent creation could have
+    /// left duplicates, which the operator has to sort out. Users without a password are logged, they move to
+    /// the file backend.
+    async fn check_unique_usernames(conn: &mut PoolConnection<Sqlite>) -> Result<(), String> {
+        let duplicates: Vec<(String, String)> = sqlx::query_as(r#"
+            SELECT
+                username,
+                group_concat(user_id, ', ')
+            FROM
+                users
+            GROUP BY
+                username
+            HAVING
+                count(*) > 1
+        "#)
+        .fetch_all(&mut *conn)
+        .await
+        .unwrap();
+
+        if !duplicates.is_empty() {
+            let duplicates: Vec<String> = duplicates
+                .into_iter()
+                .map(|(username, user_ids)| format!("{} (user ids {})", username, user_ids))
+                .collect();
+
+            return Err(format!(
+                "Database migration {} needs unique usernames, rename or delete duplicate users first: {}",
+                AUTH_BACKEND_MIGRATION, duplicates.join(", "),
+            ));
+        }
+
+        let usernames: Vec<String> = sqlx::query_scalar(r#"
+            SELECT
+                username
+            FROM
+                users
+            WHERE
+                user_id NOT IN (SELECT user_id FROM passwords)
+        "#)
+        .fetch_all(&mut *conn)
+        .await
+        .unwrap();
+
+        for username in usernames {
+            tracing::warn!("User {} has no password here, it now belongs to the file authentication backend", username);
+        }
+
+        Ok(())
+    }
+
     pub async fn find_user_by_name(&self, username: &str) -> (DatabaseConnection, Option<(UserId, DatabaseUserInfo)>) {
         let mut conn = DatabaseConnection { conn: self.pool.acquire().await.unwrap() };
 
         let res = unwrap_or_return_tuple!(conn, sqlx::query!(r#"
             SELECT
                 user_id,
-                active
+                active,
+                auth_backend
             FROM
                 users
             WHERE
@@ -111,86 +584,1581 @@ impl Database {
         let user_id: UserId = res.user_id;
         let dbi = DatabaseUserInfo {
             active: res.active,
+            auth_backend: res.auth_backend,
         };
 
         (conn, Some((user_id, dbi)))
     }
 
-    pub async fn get_user_password( &self, mut conn: DatabaseConnection, user_id: UserId ) -> (DatabaseConnection, Option<DatabaseUserPasswordInfo>) {
+    /// Id, active flag and password of a user of the database authentication backend.
+    pub async fn find_user_password(&self, username: &str) -> (DatabaseConnection, Option<(UserId, bool, DatabaseUserPasswordInfo)>) {
+        let mut conn = DatabaseConnection { conn: self.pool.acquire().await.unwrap() };
+
         let res = unwrap_or_return_tuple!(conn, sqlx::query!(r#"
             SELECT
-                password
+                users.user_id,
+                users.active,
+                passwords.password
             FROM
-                passwords
+                users
+                JOIN passwords ON passwords.user_id = users.user_id
             WHERE
-                user_id = ?
-        "#, user_id)
+                users.username = ? AND users.auth_backend = 'database'
+        "#, username)
         .fetch_one(&mut conn.conn)
         .await
         .ok());
 
-        let dbpi = DatabaseUserPasswordInfo { 
-            password: res.password 
+        let dbpi = DatabaseUserPasswordInfo {
+            password: res.password
         };
 
-        (conn, Some(dbpi))
+        (conn, Some((res.user_id, res.active, dbpi)))
+    }
+
+    pub async fn set_user_password( &self, mut conn: DatabaseConnection, user_id: UserId, password: &str ) -> (DatabaseConnection, Option<()>) {
+        let res = sqlx::query!(r#"
+            INSERT OR REPLACE INTO passwords (
+                user_id,
+                password
+            )
+            VALUES
+                (?, ?)
+        "#, user_id, password)
+        .execute(&mut conn.conn)
+        .await
+        .ok();
+
+        let res = unwrap_or_return_tuple!(conn, res).rows_affected();
+
+        (conn, (res == 1).then_some(()))
     }
 
-    pub async fn get_address_book(&self, user_id: UserId) -> Option<AddressBook> {
+    /// The row of a user of another authentication backend, without a password here. It's created on first use,
+    /// owned by `auth_backend`; an existing row may belong to another backend.
+    pub async fn find_or_create_external_user(&self, username: &str, auth_backend: &str) -> Option<(UserId, DatabaseUserInfo)> {
         let mut conn = self.pool.acquire().await.unwrap();
 
+        sqlx::query!(r#"
+            INSERT INTO users (
+                active,
+                username,
+                auth_backend
+            )
+            VALUES
+                (TRUE, ?, ?)
+            ON CONFLICT (username) DO NOTHING
+        "#, username, auth_backend)
+        .execute(&mut conn)
+        .await
+        .ok()?;
+
         let res = sqlx::query!(r#"
             SELECT
-                ab
+                user_id,
+                active,
+                auth_backend
             FROM
-                address_books
+                users
             WHERE
-                user_id = ?
-        "#, user_id)
+                username = ?
+        "#, username)
         .fetch_one(&mut conn)
         .await
         .ok()?;
 
-        let ab = AddressBook {
-            ab: res.ab,
-        };
-
-        Some(ab)
+        Some((res.user_id, DatabaseUserInfo { active: res.active, auth_backend: res.auth_backend }))
     }
 
-    pub async fn update_address_books(&self, mut values: Vec<(UserId, AddressBook)>) -> Option<()> {
+    pub async fn create_user(&self, username: &str, password: &str, active: bool) -> Option<UserId> {
         let mut tx = self.pool.begin().await.unwrap();
 
-        let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new(
-            // Note the trailing space; most calls to `QueryBuilder` don't automatically insert
-            // spaces as that might interfere with identifiers or quoted strings where exact
-            // values may matter.
-            "INSERT OR REPLACE INTO address_books (user_id, ab) "
-        );
-        
-        let values_count = values.len() as u64;
-
-        // Note that `.into_iter()` wasn't needed here since `users` is already an iterator.
-        query_builder.push_values(values.drain(..), |mut b, value| {
-            b
-            .push_bind(value.0)
-            .push_bind(value.1.ab);
-        });
-        
-        let query = query_builder.build();
-
-        let res = query
+        let user_id = sqlx::query!(r#"
+            INSERT INTO users (
+                active,
+                username
+            )
+            VALUES
+                (?, ?)
+        "#, active, username)
         .execute(&mut tx)
         .await
         .ok()?
-        .rows_affected();
+        .last_insert_rowid();
 
-        if res != values_count {
-            return None;
-        }
+        sqlx::query!(r#"
+            INSERT INTO passwords (
+                user_id,
+                password
+            )
+            VALUES
+                (?, ?)
+        "#, user_id, password)
+        .execute(&mut tx)
+        .await
+        .ok()?;
 
         tx.commit().await.ok()?;
 
+        Some(user_id)
+    }
+
+    pub async fn set_user_active(&self, user_id: UserId, active: bool) -> Option<()> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            UPDATE
+                users
+            SET
+                active = ?
+            WHERE
+                user_id = ?
+        "#, active, user_id)
+        .execute(&mut conn)
+        .await
+        .ok()?
+        .rows_affected();
+
+        (res == 1).then_some(())
+    }
+
+    pub async fn set_user_admin(&self, user_id: UserId, admin: bool) -> Option<()> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            UPDATE
+                users
+            SET
+                admin = ?
+            WHERE
+                user_id = ?
+        "#, admin, user_id)
+        .execute(&mut conn)
+        .await
+        .ok()?
+        .rows_affected();
+
+        (res == 1).then_some(())
+    }
+
+    /// The user's email address, `None` inside if the user has none.
+    pub async fn get_user_email(&self, user_id: UserId) -> Option<Option<String>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            SELECT
+                email
+            FROM
+                users
+            WHERE
+                user_id = ?
+        "#, user_id)
+        .fetch_one(&mut conn)
+        .await
+        .ok()?;
+
+        Some(res.email)
+    }
+
+    pub async fn set_user_email(&self, user_id: UserId, email: Option<&str>) -> Option<()> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            UPDATE
+                users
+            SET
+                email = ?
+            WHERE
+                user_id = ?
+        "#, email, user_id)
+        .execute(&mut conn)
+        .await
+        .ok()?
+        .rows_affected();
+
+        (res == 1).then_some(())
+    }
+
+    /// Whether the user logged in from this device before.
+    pub async fn is_known_device(&self, user_id: UserId, device_id: &str, device_uuid: &str) -> Option<bool> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            SELECT
+                COUNT(*) AS "count!: i64"
+            FROM
+                known_devices
+            WHERE
+                user_id = ? AND device_id = ? AND device_uuid = ?
+        "#, user_id, device_id, device_uuid)
+        .fetch_one(&mut conn)
+        .await
+        .ok()?;
+
+        Some(res.count != 0)
+    }
+
+    pub async fn add_known_device(&self, user_id: UserId, device_id: &str, device_uuid: &str, now: u64) -> Option<()> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let now = now as i64;
+
+        sqlx::query!(r#"
+            INSERT OR IGNORE INTO known_devices (
+                user_id,
+                device_id,
+                device_uuid,
+                first_login_at
+            ) VALUES (
+                ?, ?, ?, ?
+            )
+        "#, user_id, device_id, device_uuid, now)
+        .execute(&mut conn)
+        .await
+        .ok()?;
+
         Some(())
     }
-}
\ No newline at end of file
+
+    /// Whether the user is an active administrator.
+    pub async fn is_user_admin(&self, user_id: UserId) -> bool {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        sqlx::query!(r#"
+            SELECT
+                admin
+            FROM
+                users
+            WHERE
+                user_id = ? AND active
+        "#, user_id)
+        .fetch_one(&mut conn)
+        .await
+        .map(|r| r.admin)
+        .unwrap_or(false)
+    }
+
+    pub async fn get_user_totp(&self, user_id: UserId) -> Option<DatabaseUserTotp> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            SELECT
+                totp_secret,
+                totp_enabled,
+                totp_last_step
+            FROM
+                users
+            WHERE
+                user_id = ?
+        "#, user_id)
+        .fetch_one(&mut conn)
+        .await
+        .ok()?;
+
+        Some(DatabaseUserTotp {
+            secret: res.totp_secret,
+            enabled: res.totp_enabled,
+            last_step: res.totp_last_step as u64,
+        })
+    }
+
+    /// Start enrolment with a new secret. Two-factor authentication stays disabled until it is confirmed.
+    pub async fn set_user_totp_secret(&self, user_id: UserId, secret: &str) -> Option<()> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        sqlx::query!(r#"
+            UPDATE
+                users
+            SET
+                totp_secret = ?,
+                totp_enabled = FALSE
+            WHERE
+                user_id = ? AND NOT totp_enabled
+        "#, secret, user_id)
+        .execute(&mut conn)
+        .await
+        .ok()?;
+
+        Some(())
+    }
+
+    /// Enable two-factor authentication with the secret set before, replacing the recovery codes.
+    pub async fn enable_user_totp(&self, user_id: UserId, last_step: u64, recovery_code_hashes: &[String]) -> Option<()> {
+        let mut tx = self.pool.begin().await.unwrap();
+
+        let last_step = last_step as i64;
+
+        let res = sqlx::query!(r#"
+            UPDATE
+                users
+            SET
+                totp_enabled = TRUE,
+                totp_last_step = ?
+            WHERE
+                user_id = ? AND totp_secret IS NOT NULL
+        "#, last_step, user_id)
+        .execute(&mut tx)
+        .await
+        .ok()?
+        .rows_affected();
+
+        if res != 1 {
+            return None;
+        }
+
+        sqlx::query!(r#"
+            DELETE FROM
+                recovery_codes
+            WHERE
+                user_id = ?
+        "#, user_id)
+        .execute(&mut tx)
+        .await
+        .ok()?;
+
+        for code_hash in recovery_code_hashes {
+            sqlx::query!(r#"
+                INSERT INTO recovery_codes (
+                    user_id,
+                    code_hash
+                ) VALUES (
+                    ?, ?
+                )
+            "#, user_id, code_hash)
+            .execute(&mut tx)
+            .await
+            .ok()?;
+        }
+
+        tx.commit().await.ok()?;
+
+        Some(())
+    }
+
+    pub async fn disable_user_totp(&self, user_id: UserId) -> Option<()> {
+        let mut tx = self.pool.begin().await.unwrap();
+
+        sqlx::query!(r#"
+            UPDATE
+                users
+            SET
+                totp_secret = NULL,
+                totp_enabled = FALSE,
+                totp_last_step = 0
+            WHERE
+                user_id = ?
+        "#, user_id)
+        .execute(&mut tx)
+        .await
+        .ok()?;
+
+        sqlx::query!(r#"
+            DELETE FROM
+                recovery_codes
+            WHERE
+                user_id = ?
+        "#, user_id)
+        .execute(&mut tx)
+        .await
+        .ok()?;
+
+        tx.commit().await.ok()?;
+
+        Some(())
+    }
+
+    /// Record the time step of an accepted code. Returns false if a code of this or a later step was accepted already.
+    pub async fn set_user_totp_last_step(&self, user_id: UserId, step: u64) -> Option<bool> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let step = step as i64;
+
+        let res = sqlx::query!(r#"
+            UPDATE
+                users
+            SET
+                totp_last_step = ?
+            WHERE
+                user_id = ? AND totp_last_step < ?
+        "#, step, user_id, step)
+        .execute(&mut conn)
+        .await
+        .ok()?
+        .rows_affected();
+
+        Some(res == 1)
+    }
+
+    /// Use up a recovery code. Returns whether the user had it.
+    pub async fn use_recovery_code(&self, user_id: UserId, code_hash: &str) -> Option<bool> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            DELETE FROM
+                recovery_codes
+            WHERE
+                user_id = ? AND code_hash = ?
+        "#, user_id, code_hash)
+        .execute(&mut conn)
+        .await
+        .ok()?
+        .rows_affected();
+
+        Some(res == 1)
+    }
+
+    pub async fn list_users(&self) -> Option<Vec<DatabaseUserRecord>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            SELECT
+                user_id,
+                username,
+                active,
+                admin
+            FROM
+                users
+            ORDER BY
+                username
+        "#)
+        .fetch_all(&mut conn)
+        .await
+        .ok()?;
+
+        let users = res
+            .into_iter()
+            .map(|r| DatabaseUserRecord {
+                user_id: r.user_id,
+                username: r.username,
+                active: r.active,
+                admin: r.admin,
+            })
+            .collect();
+
+        Some(users)
+    }
+
+    pub async fn delete_user(&self, user_id: UserId) -> Option<()> {
+        let mut tx = self.pool.begin().await.unwrap();
+
+        let personal_book = sqlx::query!(r#"
+            SELECT
+                book_id
+            FROM
+                address_books
+            WHERE
+                owner_id = ?
+        "#, user_id)
+        .fetch_optional(&mut tx)
+        .await
+        .ok()?;
+
+        if let Some(book) = personal_book {
+            Self::delete_book(&mut tx, book.book_id).await?;
+        }
+
+        sqlx::query!(r#"
+            DELETE FROM
+                ab_permissions
+            WHERE
+                user_id = ?
+        "#, user_id)
+        .execute(&mut tx)
+        .await
+        .ok()?;
+
+        sqlx::query!(r#"
+            DELETE FROM
+                recovery_codes
+            WHERE
+                user_id = ?
+        "#, user_id)
+        .execute(&mut tx)
+        .await
+        .ok()?;
+
+        sqlx::query!(r#"
+            DELETE FROM
+                known_devices
+            WHERE
+                user_id = ?
+        "#, user_id)
+        .execute(&mut tx)
+        .await
+        .ok()?;
+
+        sqlx::query!(r#"
+            DELETE FROM
+                sessions
+            WHERE
+                user_id = ?
+        "#, user_id)
+        .execute(&mut tx)
+        .await
+        .ok()?;
+
+        sqlx::query!(r#"
+            DELETE FROM
+                passwords
+            WHERE
+                user_id = ?
+        "#, user_id)
+        .execute(&mut tx)
+        .await
+        .ok()?;
+
+        let res = sqlx::query!(r#"
+            DELETE FROM
+                users
+            WHERE
+                user_id = ?
+        "#, user_id)
+        .execute(&mut tx)
+        .await
+        .ok()?
+        .rows_affected();
+
+        if res != 1 {
+            return None;
+        }
+
+        tx.commit().await.ok()?;
+
+        Some(())
+    }
+
+    pub async fn load_sessions(&self) -> Option<Vec<DatabaseSessionInfo>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            SELECT
+                sessions.session_id,
+                sessions.token_hash,
+                sessions.user_id,
+                users.username,
+                sessions.device_id,
+                sessions.device_uuid,
+                sessions.ip,
+                sessions.created_at,
+                sessions.last_seen_at
+            FROM
+                sessions
+            INNER JOIN
+                users ON users.user_id = sessions.user_id
+            WHERE
+                users.active
+        "#)
+        .fetch_all(&mut conn)
+        .await
+        .ok()?;
+
+        let sessions = res
+            .into_iter()
+            .filter_map(|r| Some(DatabaseSessionInfo {
+                session_id: r.session_id,
+                token_hash: TokenHash::from_str(&r.token_hash).ok()?,
+                user_id: r.user_id,
+                username: r.username,
+                device_id: r.device_id,
+                device_uuid: r.device_uuid,
+                ip: r.ip,
+                created_at: r.created_at as u64,
+                last_seen_at: r.last_seen_at as u64,
+            }))
+            .collect();
+
+        Some(sessions)
+    }
+
+    pub async fn insert_session(&self, token_hash: TokenHash, user_id: UserId, device_id: &str, device_uuid: &str, ip: IpAddr, now: u64) -> Option<SessionId> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let token_hash = token_hash.to_base64();
+        let ip = ip.to_string();
+        let now = now as i64;
+
+        let session_id = sqlx::query!(r#"
+            INSERT INTO sessions (
+                token_hash,
+                user_id,
+                device_id,
+                device_uuid,
+                ip,
+                created_at,
+                last_seen_at
+            )
+            VALUES
+                (?, ?, ?, ?, ?, ?, ?)
+        "#, token_hash, user_id, device_id, device_uuid, ip, now, now)
+        .execute(&mut conn)
+        .await
+        .ok()?
+        .last_insert_rowid();
+
+        Some(session_id)
+    }
+
+    pub async fn delete_sessions(&self, session_ids: Vec<SessionId>) -> Option<()> {
+        let mut tx = self.pool.begin().await.unwrap();
+
+        for session_id in session_ids {
+            sqlx::query!(r#"
+                DELETE FROM
+                    sessions
+                WHERE
+                    session_id = ?
+            "#, session_id)
+            .execute(&mut tx)
+            .await
+            .ok()?;
+        }
+
+        tx.commit().await.ok()?;
+
+        Some(())
+    }
+
+    pub async fn delete_user_sessions(&self, user_id: UserId) -> Option<u64> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            DELETE FROM
+                sessions
+            WHERE
+                user_id = ?
+        "#, user_id)
+        .execute(&mut conn)
+        .await
+        .ok()?
+        .rows_affected();
+
+        Some(res)
+    }
+
+    /// Ids of sessions which are still in the database and belong to active users.
+    pub async fn get_valid_session_ids(&self) -> Option<Vec<SessionId>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            SELECT
+                sessions.session_id
+            FROM
+                sessions
+            INNER JOIN
+                users ON users.user_id = sessions.user_id
+            WHERE
+                users.active
+        "#)
+        .fetch_all(&mut conn)
+        .await
+        .ok()?;
+
+        Some(res.into_iter().map(|r| r.session_id).collect())
+    }
+
+    pub async fn update_sessions_last_seen(&self, values: Vec<(SessionId, u64)>) -> Option<()> {
+        let mut tx = self.pool.begin().await.unwrap();
+
+        for (session_id, last_seen_at) in values {
+            let last_seen_at = last_seen_at as i64;
+
+            sqlx::query!(r#"
+                UPDATE
+                    sessions
+                SET
+                    last_seen_at = ?
+                WHERE
+                    session_id = ?
+            "#, last_seen_at, session_id)
+            .execute(&mut tx)
+            .await
+            .ok()?;
+        }
+
+        tx.commit().await.ok()?;
+
+        Some(())
+    }
+
+    /// Rebuild the book from `address_books`, which holds fields we don't know about, `ab_peers` and `ab_tags`.
+    pub async fn get_address_book(&self, book_id: BookId) -> Option<(AddressBook, u64)> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            SELECT
+                ab,
+                updated_at
+            FROM
+                address_books
+            WHERE
+                book_id = ?
+        "#, book_id)
+        .fetch_one(&mut conn)
+        .await
+        .ok()?;
+
+        let tags = sqlx::query!(r#"
+            SELECT
+                tag
+            FROM
+                ab_tags
+            WHERE
+                book_id = ?
+            ORDER BY
+                position
+        "#, book_id)
+        .fetch_all(&mut conn)
+        .await
+        .ok()?
+        .into_iter()
+        .map(|r| r.tag)
+        .collect();
+
+        let peers = sqlx::query!(r#"
+            SELECT
+                peer_id,
+                username,
+                hostname,
+                platform,
+                alias,
+                tags,
+                hash,
+                other
+            FROM
+                ab_peers
+            WHERE
+                book_id = ?
+            ORDER BY
+                position
+        "#, book_id)
+        .fetch_all(&mut conn)
+        .await
+        .ok()?
+        .into_iter()
+        .map(|r| Ok(AbPeer {
+            id: r.peer_id,
+            username: r.username,
+            hostname: r.hostname,
+            platform: r.platform,
+            alias: r.alias,
+            tags: serde_json::from_str(&r.tags)?,
+            hash: r.hash,
+            other: serde_json::from_str(&r.other)?,
+        }))
+        .collect::<Result<Vec<_>, serde_json::Error>>();
+
+        let ab = peers.and_then(|peers| Ok(Ab {
+            tags,
+            peers,
+            other: serde_json::from_str(&res.ab)?,
+        }));
+
+        match ab {
+            Ok(ab) => Some((AddressBook { ab }, res.updated_at as u64)),
+            Err(err) => {
+                tracing::error!("Stored address book {} is malformed: {}", book_id, err);
+                None
+            },
+        }
+    }
+
+    async fn delete_address_book_content(tx: &mut Transaction<'_, Sqlite>, book_id: BookId) -> Option<()> {
+        sqlx::query!(r#"
+            DELETE FROM
+                ab_peers
+            WHERE
+                book_id = ?
+        "#, book_id)
+        .execute(&mut *tx)
+        .await
+        .ok()?;
+
+        sqlx::query!(r#"
+            DELETE FROM
+                ab_tags
+            WHERE
+                book_id = ?
+        "#, book_id)
+        .execute(&mut *tx)
+        .await
+        .ok()?;
+
+        Some(())
+    }
+
+    /// Replace the content of the books, all of them or none. Books deleted meanwhile are skipped.
+    pub async fn update_address_books(&self, values: Vec<(BookId, AddressBook, u64)>) -> Option<()> {
+        let mut tx = self.pool.begin().await.unwrap();
+
+        for (book_id, address_book, updated_at) in values {
+            let json = address_book.to_json();
+            let ab = address_book.ab;
+            let other = serde_json::to_string(&ab.other).ok()?;
+            let updated_at = updated_at as i64;
+
+            let res = sqlx::query!(r#"
+                UPDATE
+                    address_books
+                SET
+                    ab = ?,
+                    updated_at = ?
+                WHERE
+                    book_id = ?
+            "#, other, updated_at, book_id)
+            .execute(&mut tx)
+            .await
+            .ok()?
+            .rows_affected();
+
+            if res == 0 {
+                tracing::debug!("Address book {} was deleted, not writing it", book_id);
+                continue;
+            }
+
+            sqlx::query!(r#"
+                INSERT INTO address_book_revisions (
+                    book_id,
+                    created_at,
+                    ab
+                ) VALUES (
+                    ?, ?, ?
+                )
+            "#, book_id, updated_at, json)
+            .execute(&mut tx)
+            .await
+            .ok()?;
+
+            Self::delete_address_book_content(&mut tx, book_id).await?;
+
+            let peers: Vec<(usize, AbPeer)> = ab.peers.into_iter().enumerate().collect();
+            for chunk in peers.chunks(AB_PEERS_PER_INSERT) {
+                let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+                    "INSERT INTO ab_peers (book_id, position, peer_id, username, hostname, platform, alias, tags, hash, other) "
+                );
+
+                query_builder.push_values(chunk, |mut b, (position, peer)| {
+                    b
+                    .push_bind(book_id)
+                    .push_bind(*position as i64)
+                    .push_bind(peer.id.clone())
+                    .push_bind(peer.username.clone())
+                    .push_bind(peer.hostname.clone())
+                    .push_bind(peer.platform.clone())
+                    .push_bind(peer.alias.clone())
+                    .push_bind(serde_json::to_string(&peer.tags).unwrap())
+                    .push_bind(peer.hash.clone())
+                    .push_bind(serde_json::to_string(&peer.other).unwrap());
+                });
+
+                let res = query_builder
+                .build()
+                .execute(&mut tx)
+                .await
+                .ok()?
+                .rows_affected();
+
+                if res != chunk.len() as u64 {
+                    return None;
+                }
+            }
+
+            let tags: Vec<(usize, String)> = ab.tags.into_iter().enumerate().collect();
+            for chunk in tags.chunks(AB_TAGS_PER_INSERT) {
+                let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+                    "INSERT INTO ab_tags (book_id, position, tag) "
+                );
+
+                query_builder.push_values(chunk, |mut b, (position, tag)| {
+                    b
+                    .push_bind(book_id)
+                    .push_bind(*position as i64)
+                    .push_bind(tag.clone());
+                });
+
+                let res = query_builder
+                .build()
+                .execute(&mut tx)
+                .await
+                .ok()?
+                .rows_affected();
+
+                if res != chunk.len() as u64 {
+                    return None;
+                }
+            }
+        }
+
+        tx.commit().await.ok()?;
+
+        Some(())
+    }
+
+    /// Stored versions of the book, newest first.
+    pub async fn list_address_book_revisions(&self, book_id: BookId) -> Option<Vec<AbRevisionInfo>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            SELECT
+                revision_id,
+                created_at,
+                json_array_length(ab, '$.peers') AS "peers!: i64",
+                json_array_length(ab, '$.tags') AS "tags!: i64"
+            FROM
+                address_book_revisions
+            WHERE
+                book_id = ?
+            ORDER BY
+                revision_id DESC
+        "#, book_id)
+        .fetch_all(&mut conn)
+        .await
+        .ok()?
+        .into_iter()
+        .map(|r| AbRevisionInfo {
+            revision_id: r.revision_id,
+            created_at: r.created_at as u64,
+            peers: r.peers as usize,
+            tags: r.tags as usize,
+        })
+        .collect();
+
+        Some(res)
+    }
+
+    pub async fn get_address_book_revision(&self, book_id: BookId, revision_id: i64) -> Option<AddressBook> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            SELECT
+                ab
+            FROM
+                address_book_revisions
+            WHERE
+                book_id = ? AND revision_id = ?
+        "#, book_id, revision_id)
+        .fetch_one(&mut conn)
+        .await
+        .ok()?;
+
+        AddressBook::from_json(&res.ab).ok()
+    }
+
+    /// All stored revisions of the book, newest first.
+    pub async fn get_address_book_revisions(&self, book_id: BookId) -> Option<Vec<AddressBook>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            SELECT
+                ab
+            FROM
+                address_book_revisions
+            WHERE
+                book_id = ?
+            ORDER BY
+                revision_id DESC
+        "#, book_id)
+        .fetch_all(&mut conn)
+        .await
+        .ok()?
+        .into_iter()
+        .filter_map(|r| AddressBook::from_json(&r.ab).ok())
+        .collect();
+
+        Some(res)
+    }
+
+    /// Delete revisions beyond the newest `keep` of each book, and those created before `created_before`.
+    /// The newest revision of each book always stays.
+    pub async fn prune_address_book_revisions(&self, keep: usize, created_before: u64) -> Option<u64> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let keep = i64::try_from(keep).unwrap_or(i64::MAX);
+        let created_before = created_before as i64;
+
+        let res = sqlx::query!(r#"
+            DELETE FROM
+                address_book_revisions
+            WHERE
+                revision_id IN (
+                    SELECT revision_id FROM (
+                        SELECT
+                            revision_id,
+                            created_at,
+                            ROW_NUMBER() OVER (PARTITION BY book_id ORDER BY revision_id DESC) AS number
+                        FROM
+                            address_book_revisions
+                    )
+                    WHERE number > 1 AND (number > ? OR created_at < ?)
+                )
+        "#, keep, created_before)
+        .execute(&mut conn)
+        .await
+        .ok()?
+        .rows_affected();
+
+        Some(res)
+    }
+
+    /// Id and guid of the user's personal book, created empty on first use.
+    pub async fn get_personal_book(&self, user_id: UserId) -> Option<(BookId, String)> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        sqlx::query!(r#"
+            INSERT OR IGNORE INTO address_books (
+                guid,
+                owner_id,
+                name,
+                ab,
+                updated_at
+            ) VALUES (
+                lower(hex(randomblob(4)) || '-' || hex(randomblob(2)) || '-' || hex(randomblob(2)) || '-' || hex(randomblob(2)) || '-' || hex(randomblob(6))),
+                ?, 'My address book', '{}', 0
+            )
+        "#, user_id)
+        .execute(&mut conn)
+        .await
+        .ok()?;
+
+        let res = sqlx::query!(r#"
+            SELECT
+                book_id,
+                guid
+            FROM
+                address_books
+            WHERE
+                owner_id = ?
+        "#, user_id)
+        .fetch_one(&mut conn)
+        .await
+        .ok()?;
+
+        Some((res.book_id, res.guid))
+    }
+
+    /// A book by its guid, with the user's access to it: full for the owner of a personal book,
+    /// as granted for shared books. `None` if there is no such book or the user has no access.
+    pub async fn find_book_by_guid(&self, guid: &str, user_id: UserId) -> Option<(BookId, AbRule)> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            SELECT
+                address_books.book_id,
+                CASE
+                    WHEN address_books.owner_id = ? THEN 3
+                    ELSE ab_permissions.rule
+                END AS "rule: i64"
+            FROM
+                address_books
+            LEFT JOIN
+                ab_permissions ON ab_permissions.book_id = address_books.book_id AND ab_permissions.user_id = ?
+            WHERE
+                address_books.guid = ?
+        "#, user_id, user_id, guid)
+        .fetch_optional(&mut conn)
+        .await
+        .ok()??;
+
+        Some((res.book_id, AbRule::from_i64(res.rule?)?))
+    }
+
+    /// Shared books the user has access to, or all of them without a user.
+    pub async fn list_shared_books(&self, user_id: Option<UserId>) -> Option<Vec<DatabaseBookRecord>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            SELECT
+                address_books.book_id,
+                address_books.guid,
+                address_books.name,
+                address_books.note,
+                MAX(ab_permissions.rule) AS "rule: i64",
+                COUNT(ab_permissions.user_id) AS "users!: i64"
+            FROM
+                address_books
+            LEFT JOIN
+                ab_permissions ON ab_permissions.book_id = address_books.book_id AND (?1 IS NULL OR ab_permissions.user_id = ?1)
+            WHERE
+                address_books.owner_id IS NULL
+            GROUP BY
+                address_books.book_id
+            HAVING
+                ?1 IS NULL OR COUNT(ab_permissions.user_id) > 0
+            ORDER BY
+                address_books.name
+        "#, user_id)
+        .fetch_all(&mut conn)
+        .await
+        .ok()?;
+
+        let books = res
+            .into_iter()
+            .map(|r| DatabaseBookRecord {
+                book_id: r.book_id,
+                guid: r.guid,
+                name: r.name,
+                note: r.note,
+                rule: r.rule.and_then(AbRule::from_i64),
+                users: r.users,
+            })
+            .collect();
+
+        Some(books)
+    }
+
+    pub async fn find_shared_book_by_name(&self, name: &str) -> Option<BookId> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            SELECT
+                book_id
+            FROM
+                address_books
+            WHERE
+                name = ? AND owner_id IS NULL
+        "#, name)
+        .fetch_optional(&mut conn)
+        .await
+        .ok()??;
+
+        Some(res.book_id)
+    }
+
+    pub async fn create_shared_book(&self, name: &str, note: &str) -> Option<BookId> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let book_id = sqlx::query!(r#"
+            INSERT INTO address_books (
+                guid,
+                name,
+                note,
+                ab,
+                updated_at
+            ) VALUES (
+                lower(hex(randomblob(4)) || '-' || hex(randomblob(2)) || '-' || hex(randomblob(2)) || '-' || hex(randomblob(2)) || '-' || hex(randomblob(6))),
+                ?, ?, '{}', 0
+            )
+        "#, name, note)
+        .execute(&mut conn)
+        .await
+        .ok()?
+        .last_insert_rowid();
+
+        Some(book_id)
+    }
+
+    /// Delete a book with its revisions and permissions.
+    pub async fn delete_shared_book(&self, book_id: BookId) -> Option<()> {
+        let mut tx = self.pool.begin().await.unwrap();
+
+        Self::delete_book(&mut tx, book_id).await?;
+
+        tx.commit().await.ok()?;
+
+        Some(())
+    }
+
+    async fn delete_book(tx: &mut Transaction<'_, Sqlite>, book_id: BookId) -> Option<()> {
+        Self::delete_address_book_content(&mut *tx, book_id).await?;
+
+        sqlx::query!(r#"
+            DELETE FROM
+                address_book_revisions
+            WHERE
+                book_id = ?
+        "#, book_id)
+        .execute(&mut *tx)
+        .await
+        .ok()?;
+
+        sqlx::query!(r#"
+            DELETE FROM
+                ab_permissions
+            WHERE
+                book_id = ?
+        "#, book_id)
+        .execute(&mut *tx)
+        .await
+        .ok()?;
+
+        let res = sqlx::query!(r#"
+            DELETE FROM
+                address_books
+            WHERE
+                book_id = ?
+        "#, book_id)
+        .execute(&mut *tx)
+        .await
+        .ok()?
+        .rows_affected();
+
+        if res != 1 {
+            return None;
+        }
+
+        Some(())
+    }
+
+    pub async fn set_book_permission(&self, book_id: BookId, user_id: UserId, rule: AbRule) -> Option<()> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let rule = rule as i64;
+
+        sqlx::query!(r#"
+            INSERT OR REPLACE INTO ab_permissions (
+                book_id,
+                user_id,
+                rule
+            ) VALUES (
+                ?, ?, ?
+            )
+        "#, book_id, user_id, rule)
+        .execute(&mut conn)
+        .await
+        .ok()?;
+
+        Some(())
+    }
+
+    /// Returns whether the user had access.
+    pub async fn remove_book_permission(&self, book_id: BookId, user_id: UserId) -> Option<bool> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let res = sqlx::query!(r#"
+            DELETE FROM
+                ab_permissions
+            WHERE
+                book_id = ? AND user_id = ?
+        "#, book_id, user_id)
+        .execute(&mut conn)
+        .await
+        .ok()?
+        .rows_affected();
+
+        Some(res > 0)
+    }
+
+    /// Store audit events, and open, update or close the connection sessions the connection audits belong to.
+    pub async fn insert_audit_events(&self, values: &[AuditEvent]) -> Option<()> {
+        let mut tx = self.pool.begin().await.unwrap();
+
+        for chunk in values.chunks(AUDIT_EVENTS_PER_INSERT) {
+            let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+                "INSERT INTO audit_events (kind, created_at, source_ip, user_id, conn_id, action, peer_id, peer_ip, uuid, payload) "
+            );
+
+            query_builder.push_values(chunk, |mut b, event| {
+                b
+                .push_bind(event.kind.as_str())
+                .push_bind(event.created_at as i64)
+                .push_bind(event.source_ip.to_string())
+                .push_bind(event.user_id)
+                .push_bind(event.conn_id)
+                .push_bind(event.action.clone())
+                .push_bind(event.peer_id.clone())
+                .push_bind(event.peer_ip.clone())
+                .push_bind(event.uuid.clone())
+                .push_bind(event.payload.clone());
+            });
+
+            let res = query_builder
+            .build()
+            .execute(&mut tx)
+            .await
+            .ok()?
+            .rows_affected();
+
+            if res != chunk.len() as u64 {
+                return None;
+            }
+        }
+
+        for event in values {
+            let conn_session = match &event.conn_session {
+                Some(conn_session) => conn_session,
+                None => continue,
+            };
+
+            let created_at = event.created_at as i64;
+
+            match conn_session {
+                ConnSessionEvent::Open { ip } => {
+                    sqlx::query!(r#"
+                        INSERT INTO conn_sessions (
+                            device_id,
+                            device_uuid,
+                            conn_id,
+                            ip,
+                            opened_at
+                        ) VALUES (
+                            ?, ?, ?, ?, ?
+                        )
+                    "#, event.peer_id, event.uuid, event.conn_id, ip, created_at)
+                    .execute(&mut tx)
+                    .await
+                    .ok()?;
+                },
+                ConnSessionEvent::Authorized { peer_id, peer_name, conn_type } => {
+                    sqlx::query!(r#"
+                        UPDATE
+                            conn_sessions
+                        SET
+                            peer_id = ?,
+                            peer_name = ?,
+                            conn_type = ?
+                        WHERE
+                            conn_session_id = (
+                                SELECT MAX(conn_session_id) FROM conn_sessions
+                                WHERE device_id = ? AND device_uuid = ? AND conn_id = ? AND closed_at IS NULL
+                            )
+                    "#, peer_id, peer_name, conn_type, event.peer_id, event.uuid, event.conn_id)
+                    .execute(&mut tx)
+                    .await
+                    .ok()?;
+                },
+                ConnSessionEvent::Close => {
+                    sqlx::query!(r#"
+                        UPDATE
+                            conn_sessions
+                        SET
+                            closed_at = ?,
+                            duration = MAX(? - opened_at, 0)
+                        WHERE
+                            conn_session_id = (
+                                SELECT MAX(conn_session_id) FROM conn_sessions
+                                WHERE device_id = ? AND device_uuid = ? AND conn_id = ? AND closed_at IS NULL
+                            )
+                    "#, created_at, created_at, event.peer_id, event.uuid, event.conn_id)
+                    .execute(&mut tx)
+                    .await
+                    .ok()?;
+                },
+            }
+        }
+
+        tx.commit().await.ok()?;
+
+        Some(())
+    }
+
+    /// Audit events matching `filter`, newest first.
+    pub async fn find_audit_events(&self, filter: &AuditFilter) -> Option<Vec<AuditEventRecord>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "SELECT audit_events.event_id, audit_events.kind, audit_events.created_at, audit_events.source_ip, \
+                audit_events.user_id, users.username, audit_events.conn_id, audit_events.action, \
+                audit_events.peer_id, audit_events.peer_ip, audit_events.uuid, audit_events.payload \
+            FROM audit_events \
+            LEFT JOIN users ON users.user_id = audit_events.user_id \
+            WHERE 1 = 1"
+        );
+
+        if let Some(kind) = &filter.kind {
+            query_builder.push(" AND audit_events.kind = ").push_bind(kind.clone());
+        }
+        if let Some(username) = &filter.username {
+            query_builder.push(" AND users.username = ").push_bind(username.clone());
+        }
+        if let Some(peer_id) = &filter.peer_id {
+            query_builder.push(" AND audit_events.peer_id = ").push_bind(peer_id.clone());
+        }
+        if let Some(uuid) = &filter.uuid {
+            query_builder.push(" AND audit_events.uuid = ").push_bind(uuid.clone());
+        }
+        if let Some(action) = &filter.action {
+            query_builder.push(" AND audit_events.action = ").push_bind(action.clone());
+        }
+        if let Some(ip) = &filter.ip {
+            query_builder
+                .push(" AND (audit_events.source_ip = ").push_bind(ip.clone())
+                .push(" OR audit_events.peer_ip = ").push_bind(ip.clone())
+                .push(")");
+        }
+        if let Some(from) = filter.from {
+            query_builder.push(" AND audit_events.created_at >= ").push_bind(from as i64);
+        }
+        if let Some(to) = filter.to {
+            query_builder.push(" AND audit_events.created_at < ").push_bind(to as i64);
+        }
+        if let Some(cursor) = filter.cursor {
+            query_builder.push(" AND audit_events.event_id < ").push_bind(cursor);
+        }
+
+        query_builder
+            .push(" ORDER BY audit_events.event_id DESC LIMIT ")
+            .push_bind(filter.limit as i64);
+
+        let rows = query_builder
+            .build()
+            .fetch_all(&mut conn)
+            .await
+            .ok()?;
+
+        rows
+            .iter()
+            .map(AuditEventRecord::from_row)
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+    }
+
+    /// Connection sessions matching `filter`, newest first. `peer_id` matches either side of the connection,
+    /// `from` and `to` bound the opening time; user, kind and action don't apply.
+    pub async fn find_conn_sessions(&self, filter: &AuditFilter) -> Option<Vec<ConnSessionRecord>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "SELECT conn_session_id, device_id, device_uuid, conn_id, peer_id, peer_name, conn_type, \
+                ip, opened_at, closed_at, duration \
+            FROM conn_sessions \
+            WHERE 1 = 1"
+        );
+
+        if let Some(peer_id) = &filter.peer_id {
+            query_builder
+                .push(" AND (device_id = ").push_bind(peer_id.clone())
+                .push(" OR peer_id = ").push_bind(peer_id.clone())
+                .push(")");
+        }
+        if let Some(uuid) = &filter.uuid {
+            query_builder.push(" AND device_uuid = ").push_bind(uuid.clone());
+        }
+        if let Some(ip) = &filter.ip {
+            query_builder.push(" AND ip = ").push_bind(ip.clone());
+        }
+        if let Some(from) = filter.from {
+            query_builder.push(" AND opened_at >= ").push_bind(from as i64);
+        }
+        if let Some(to) = filter.to {
+            query_builder.push(" AND opened_at < ").push_bind(to as i64);
+        }
+        if let Some(cursor) = filter.cursor {
+            query_builder.push(" AND conn_session_id < ").push_bind(cursor);
+        }
+
+        query_builder
+            .push(" ORDER BY conn_session_id DESC LIMIT ")
+            .push_bind(filter.limit as i64);
+
+        let rows = query_builder
+            .build()
+            .fetch_all(&mut conn)
+            .await
+            .ok()?;
+
+        rows
+            .iter()
+            .map(ConnSessionRecord::from_row)
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+    }
+}
+
+#[cfg(test)]
+impl Database {
+    /// A new database in a temporary file, for tests.
+    pub async fn open_temporary(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("rustdesk-api-test-{}-{}.db", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        Self::open(&path).await.unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rocket::async_test]
+    async fn auth_backend_migration() {
+        let path = std::env::temp_dir().join(format!("rustdesk-api-test-{}-migration.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = SqlitePool::connect_with(SqliteConnectOptions::new().filename(&path).create_if_missing(true)).await.unwrap();
+
+        Database::init_db(&pool).await;
+        Database::migrate_db(&pool, AUTH_BACKEND_MIGRATION - 1).await.unwrap();
+
+        sqlx::query(r#"
+            INSERT INTO users (user_id, active, username) VALUES (1, TRUE, 'alice'), (2, TRUE, 'alice'), (3, TRUE, 'bob');
+            INSERT INTO passwords (user_id, password) VALUES (1, 'secret'), (2, 'secret');
+        "#)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let err = Database::migrate_db(&pool, MIGRATIONS.len()).await.unwrap_err();
+        assert!(err.contains("alice (user ids 1, 2)"), "{}", err);
+
+        sqlx::query("DELETE FROM passwords WHERE user_id = 2; DELETE FROM users WHERE user_id = 2").execute(&pool).await.unwrap();
+        Database::migrate_db(&pool, MIGRATIONS.len()).await.unwrap();
+
+        let backends: Vec<(String, String)> = sqlx::query_as("SELECT username, auth_backend FROM users ORDER BY user_id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(backends, [("alice".to_string(), "database".to_string()), ("bob".to_string(), "file".to_string())]);
+
+        let res = sqlx::query("INSERT INTO users (active, username) VALUES (TRUE, 'alice')").execute(&pool).await;
+        assert!(res.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn address_book_tables() {
+        let db = Database::open_temporary("ab_tables").await;
+        let user_id = db.create_user("alice", "password", true).await.unwrap();
+        let (book_id, _) = db.get_personal_book(user_id).await.unwrap();
+
+        let address_book = AddressBook::from_json(
+            r#"{"tags":["b","a"],"peers":[{"id":"2","tags":["a"],"forceAlwaysRelay":"true"},{"id":"1","alias":"one"}],"tag_colors":"{}"}"#
+        ).unwrap();
+        db.update_address_books(vec![(book_id, address_book.clone(), 1000)]).await.unwrap();
+        assert_eq!(db.get_address_book(book_id).await.unwrap(), (address_book, 1000));
+
+        let peers: Vec<(String, String)> = sqlx::query_as("SELECT peer_id, other FROM ab_peers WHERE book_id = ? ORDER BY position")
+            .bind(book_id)
+            .fetch_all(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(peers, [("2".to_string(), r#"{"forceAlwaysRelay":"true"}"#.to_string()), ("1".to_string(), "{}".to_string())]);
+
+        // Peers and tags which are gone are removed from the tables.
+        let address_book = AddressBook::from_json(r#"{"tags":["a"],"peers":[{"id":"1"}]}"#).unwrap();
+        db.update_address_books(vec![(book_id, address_book.clone(), 2000)]).await.unwrap();
+        assert_eq!(db.get_address_book(book_id).await.unwrap(), (address_book, 2000));
+
+        let rows: (i64, i64) = sqlx::query_as("SELECT (SELECT count(*) FROM ab_peers), (SELECT count(*) FROM ab_tags)")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(rows, (1, 1));
+    }
+
+    #[rocket::async_test]
+    async fn shared_book_permissions() {
+        let db = Database::open_temporary("shared_books").await;
+        let alice = db.create_user("alice", "password", true).await.unwrap();
+        let bob = db.create_user("bob", "password", true).await.unwrap();
+        let (personal_id, personal_guid) = db.get_personal_book(alice).await.unwrap();
+        let book_id = db.create_shared_book("team", "Team peers").await.unwrap();
+
+        db.set_book_permission(book_id, alice, AbRule::Read).await.unwrap();
+        db.set_book_permission(book_id, alice, AbRule::ReadWrite).await.unwrap();
+
+        let books = db.list_shared_books(None).await.unwrap();
+        assert_eq!(books.len(), 1);
+        assert_eq!((books[0].book_id, books[0].name.as_str(), books[0].users), (book_id, "team", 1));
+        let guid = books[0].guid.clone();
+
+        assert_eq!(db.find_book_by_guid(&guid, alice).await, Some((book_id, AbRule::ReadWrite)));
+        assert_eq!(db.find_book_by_guid(&guid, bob).await, None);
+        assert_eq!(db.find_book_by_guid(&personal_guid, alice).await, Some((personal_id, AbRule::Full)));
+        assert_eq!(db.find_book_by_guid(&personal_guid, bob).await, None);
+        assert_eq!(db.list_shared_books(Some(alice)).await.unwrap()[0].rule, Some(AbRule::ReadWrite));
+        assert!(db.list_shared_books(Some(bob)).await.unwrap().is_empty());
+
+        assert_eq!(db.remove_book_permission(book_id, alice).await, Some(true));
+        assert_eq!(db.remove_book_permission(book_id, alice).await, Some(false));
+        assert_eq!(db.find_book_by_guid(&guid, alice).await, None);
+
+        db.delete_shared_book(book_id).await.unwrap();
+        assert!(db.list_shared_books(None).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+use std::path::PathBuf;
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+};
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    message::{Mailbox, header::ContentType},
+};
+use crate::config::{MailConfig, MailTransport};
+
+/// Sends plain text messages, e.g. verification codes.
+#[rocket::async_trait]
+pub trait MailSender: Send + Sync {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String>;
+}
+
+/// The sender configured by `mail.transport`, `None` if there is none.
+pub fn mail_sender(config: &MailConfig) -> Result<Option<Box<dyn MailSender>>, String> {
+    let sender: Box<dyn MailSender> = match config.transport {
+        None => return Ok(None),
+        Some(MailTransport::Smtp) => Box::new(SmtpMailSender::new(config)?),
+        Some(MailTransport::File) => Box::new(FileMailSender { path: Some(config.file.clone()) }),
+        Some(MailTransport::Stdout) => Box::new(FileMailSender { path: None }),
+    };
+
+    Ok(Some(sender))
+}
+
+pub struct SmtpMailSender {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailSender {
+    pub fn new(config: &MailConfig) -> Result<Self, String> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.smtp_url)
+            .map_err(|e| format!("Invalid mail.smtp_url: {}", e))?
+            .build();
+
+        let from = config.from
+            .parse()
+            .map_err(|e| format!("Invalid mail.from {:?}: {}", config.from, e))?;
+
+        Ok(Self { transport, from })
+    }
+}
+
+#[rocket::async_trait]
+impl MailSender for SmtpMailSender {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        let to: Mailbox = to
+            .parse()
+            .map_err(|e| format!("Invalid address {:?}: {}", to, e))?;
+
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body.to_string())
+            .map_err(|e| format!("Failed to build message: {}", e))?;
+
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| format!("Failed to send message: {}", e))?;
+
+        Ok(())
+    }
+}
+
+/// Appends messages to a file, or prints them if there's no path.
+pub struct FileMailSender {
+    path: Option<PathBuf>,
+}
+
+#[rocket::async_trait]
+impl MailSender for FileMailSender {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        let message = format!("To: {}\nSubject: {}\n\n{}\n\n", to, subject, body);
+
+        let path = match &self.path {
+            Some(path) => path,
+            None => {
+                let mut stdout = tokio::io::stdout();
+                stdout.write_all(message.as_bytes())
+                    .await
+                    .map_err(|e| format!("Failed to print message: {}", e))?;
+                return stdout.flush()
+                    .await
+                    .map_err(|e| format!("Failed to print message: {}", e));
+            },
+        };
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+
+        file.write_all(message.as_bytes())
+            .await
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+        // Tokio writes in the background, wait until the message is in the file.
+        file.flush()
+            .await
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rocket::async_test]
+    async fn file_transport() {
+        assert!(mail_sender(&MailConfig::default()).unwrap().is_none());
+
+        let path = std::env::temp_dir().join(format!("rustdesk-api-test-{}-mail.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = MailConfig { transport: Some(MailTransport::File), file: path.clone(), ..Default::default() };
+        let sender = mail_sender(&config).unwrap().unwrap();
+
+        sender.send("alice@example.com", "First", "One").await.unwrap();
+        sender.send("bob@example.com", "Second", "Two").await.unwrap();
+
+        let messages = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(messages, "To: alice@example.com\nSubject: First\n\nOne\n\nTo: bob@example.com\nSubject: Second\n\nTwo\n\n");
+    }
+}
@@ -3,27 +3,61 @@ mod state;
 mod database;
 mod api;
 mod bearer;
+mod passwords;
+mod cli;
+mod config;
+mod client_addr;
+mod audit_log;
+mod ab_merge;
+mod ab_diff;
+mod ab_edit;
+mod ab_transfer;
+mod totp;
+mod mail;
+mod auth;
 
 use rocket::{
-    self, routes, post, Build, State, Rocket,
-    data::{Limits, ToByteUnit},
-    serde::{json::Json},
+    self, routes, get, post, put, delete, Build, State, Rocket, Either,
+    serde::{DeserializeOwned, json::{Json, Value}},
     response::status,
-    config::LogLevel, 
+    http::Status,
+    fairing::AdHoc,
+    data::{Data, Limits},
+    figment::Figment,
 };
 
-use crate::{
-    bearer::AuthenticatedUser,
-    database::Database,
-    state::{UserPasswordInfo},
+use std::{
+    sync::Arc,
+    net::IpAddr,
+    time::{Duration, UNIX_EPOCH},
 };
+use clap::Parser;
+use sha2::{Sha256, Digest};
+use serde_json::Map;
 
+use crate::{
+    cli::Cli,
+    config::{ApiConfig, AUDIT_PAYLOAD_LIMIT},
+    client_addr::{ClientAddr, TrustedProxies},
+    auth::AuthChain,
+    audit_log::{AuditEvent, AuditFilter, AuditRecord, AuditEventRecord, ConnSessionRecord, to_csv},
+    state::{secs_from_epoch, UserId},
+    bearer::{AuthenticatedUser, AdminUser},
+    database::{Database, DatabaseLock},
+    state::{UserPasswordInfo, AddressBookSnapshot, AddressBookError, BookId, SessionId, LoginOutcome, TfaError},
+};
 
-use tracing_subscriber;
 
 use crate::{
     state::ApiState,
-    api::{LoginRequest, LoginReply, AbGetResponse, AbRequest, AuditRequest, CurrentUserRequest, CurrentUserResponse, UserInfo, LogoutReply},
+    api::{LoginRequest, LoginReply, Ab, AbDiff, AbRevisionInfo, AbGetResponse, AbRequest, ErrorReply, Conditions, Conditional, AuditRequest, CurrentUserRequest, CurrentUserResponse, LogoutReply},
+    api::{ConnAuditRequest, FileAuditRequest, AlarmAuditRequest},
+    api::{AuditQuery, AuditFormat, AuditPageReply, AuditPageCsv, AUDIT_QUERY_DEFAULT_LIMIT, AUDIT_QUERY_MAX_LIMIT},
+    api::{AbPeer, AbTag, AbRule, AbProfile, AbPageReply, Pagination},
+    api::{AbSettingsReply, AbPersonalReply, AbTagRenameRequest},
+    api::{DeviceSessions, UserSessionsReply, RevokeSessionsReply},
+    api::{AbFormat, AbImportMode, AbTransferQuery, AbExport, AbImportReply, AbImportRowError, AbImportErrorReply},
+    api::{TfaSetupReply, TfaCodeRequest, TfaEnableReply},
 };
 
 macro_rules! unwrap_or_return {
@@ -40,135 +74,809 @@ macro_rules! unwrap_or_return {
 
 pub(crate) use unwrap_or_return;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct AddressBook {
-    ab: String,
+    ab: Ab,
 }
 
 impl AddressBook {
     pub fn empty() -> Self {
-        Self { 
-            ab: "{}".to_string() 
+        Self::default()
+    }
+
+    /// Parse a book in the format clients upload it.
+    pub fn from_json(data: &str) -> Result<Self, serde_json::Error> {
+        Ok(Self {
+            ab: serde_json::from_str(data)?
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        // Nothing in `Ab` can fail to serialize.
+        serde_json::to_string(&self.ab).unwrap()
+    }
+
+    /// Three-way merge of two books based on `base`, `None` if they conflict.
+    pub fn merge(base: &Self, ours: &Self, theirs: &Self) -> Option<Self> {
+        Some(Self {
+            ab: ab_merge::merge(&base.ab, &ours.ab, &theirs.ab)?
+        })
+    }
+
+    pub fn diff(from: &Self, to: &Self) -> AbDiff {
+        ab_diff::diff(&from.ab, &to.ab)
+    }
+
+    /// Hash of the content, as used in `ETag` headers.
+    pub fn etag(&self) -> String {
+        base64::encode_config(Sha256::digest(self.to_json()), base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn export(&self, format: AbFormat) -> Option<String> {
+        match format {
+            AbFormat::Json => Some(ab_transfer::export_json(&self.ab)),
+            AbFormat::Csv => ab_transfer::export_csv(&self.ab),
         }
     }
+
+    /// Import peers from an exported file, see [`ab_transfer::import`].
+    pub fn import(&mut self, data: &str, format: AbFormat, mode: AbImportMode) -> Result<AbImportReply, Vec<AbImportRowError>> {
+        let import = match format {
+            AbFormat::Json => ab_transfer::parse_json(data)?,
+            AbFormat::Csv => ab_transfer::parse_csv(data)?,
+        };
+
+        ab_transfer::import(&mut self.ab, import, mode)
+    }
+
+    /// Tags with their colors. Tags without a color get one derived from the name.
+    pub fn tags(&self) -> Vec<AbTag> {
+        let colors = ab_edit::tag_colors(&self.ab);
+
+        self.ab.tags
+            .iter()
+            .map(|name| {
+                let color = colors.get(name).and_then(Value::as_i64).unwrap_or_else(|| {
+                    let hash = Sha256::digest(name);
+                    0xFF000000 | i64::from(hash[0]) << 16 | i64::from(hash[1]) << 8 | i64::from(hash[2])
+                });
+                AbTag { name: name.clone(), color }
+            })
+            .collect()
+    }
+}
+
+fn error_reply(status: Status, error: &str) -> status::Custom<Json<ErrorReply>> {
+    status::Custom(status, Json(ErrorReply { error: error.to_string() }))
+}
+
+fn address_book_error_reply(err: AddressBookError) -> status::Custom<Json<ErrorReply>> {
+    match err {
+        AddressBookError::Conflict => error_reply(Status::Conflict, "Address book was changed by another client, reload it"),
+        AddressBookError::Unavailable => error_reply(Status::InternalServerError, "Address book is unavailable"),
+        AddressBookError::Rejected(err) => error_reply(Status::BadRequest, &err.to_string()),
+    }
+}
+
+fn tfa_error_reply(err: TfaError) -> status::Custom<Json<ErrorReply>> {
+    match err {
+        TfaError::AlreadyEnabled => error_reply(Status::Conflict, "Two-factor authentication is already enabled"),
+        TfaError::NotEnabled => error_reply(Status::Conflict, "Two-factor authentication is not enabled"),
+        TfaError::NotEnrolled => error_reply(Status::Conflict, "Set up two-factor authentication first"),
+        TfaError::InvalidCode => error_reply(Status::Forbidden, "Invalid code"),
+        TfaError::Unavailable => error_reply(Status::InternalServerError, "Two-factor authentication is unavailable"),
+    }
 }
 
-async fn build_rocket() -> Rocket<Build> {
+async fn build_rocket(figment: Figment, config: ApiConfig) -> Result<Rocket<Build>, String> {
     tracing_subscriber::fmt::init();
 
-    let figment = rocket::Config::figment()
-        .merge(("address", "0.0.0.0"))
-        .merge(("port", 21114))
-        .merge(("log_level", LogLevel::Debug))
-        .merge(("tls.certs", "rustdesk.crt"))
-        .merge(("tls.key", "rustdesk.pem"))
-        .merge(("limits", Limits::new().limit("json", 2.mebibytes())));
+    let mut rocket_config = rocket::Config::from(&figment);
+    if config.plain_http {
+        rocket_config.tls = None;
+    }
+
+    let trusted_proxies = TrustedProxies::new(&config.trusted_proxies, rocket_config.tls.is_some())?;
+
+    let db_lock = DatabaseLock::acquire(&config.database)?;
+    let db = Database::open( &config.database ).await?;
+    let mail_sender = mail::mail_sender(&config.mail)?;
+    if config.login.email_verification && mail_sender.is_none() {
+        return Err("login.email_verification needs a mail.transport to send codes".to_string());
+    }
+    let auth = AuthChain::new(&config.auth, &db)?;
+    let state = Arc::new(ApiState::new( db, config.sessions, config.address_books, config.login, mail_sender, auth ));
+    state.restore_sessions().await;
 
-    let db = Database::open( ".api.db" ).await;
-    let state = ApiState::new( db );
+    let maintenance_interval = Duration::from_secs(config.maintenance_interval.max(1));
 
-    rocket::custom(figment)
+    let rocket = rocket::custom(rocket_config)
         .mount("/api", routes![
             login, 
             ab_get, 
             ab, 
             current_user,
             audit, 
+            audit_conn,
+            audit_file,
+            audit_alarm,
+            audit_query,
+            audit_sessions,
+            admin_ab_revisions,
+            admin_ab_diff,
+            admin_ab_restore,
+            admin_user_sessions,
+            sessions,
+            revoke_session,
+            revoke_other_sessions,
+            tfa_setup,
+            tfa_enable,
+            tfa_disable,
+            ab_settings,
+            ab_personal,
+            ab_shared_profiles,
+            ab_peers,
+            ab_tags,
+            ab_peer_add,
+            ab_peer_update,
+            ab_peer_delete,
+            ab_tag_add,
+            ab_tag_rename,
+            ab_tag_update,
+            ab_tag_delete,
+            ab_export,
+            ab_import,
             logout
         ])
         .manage( state )
+        .manage( trusted_proxies )
+        .manage( db_lock )
+        .attach(AdHoc::on_liftoff("Maintenance", move |rocket| Box::pin(async move {
+            let state = rocket.state::<Arc<ApiState>>().unwrap().clone();
+            ApiState::spawn_maintenance(state, maintenance_interval);
+        })));
+
+    Ok(rocket)
 }
 
-#[rocket::launch]
-async fn launch_rocket() -> _{
-    build_rocket().await
+#[rocket::main]
+async fn main() {
+    let mut cli = Cli::parse();
+
+    let figment = cli.figment();
+    let config: ApiConfig = match figment.extract() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            std::process::exit(1);
+        },
+    };
+
+    match cli.command.take() {
+        Some(command) => {
+            if let Err(err) = cli::run(&config, command).await {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            let rocket = match build_rocket(figment, config).await {
+                Ok(rocket) => rocket,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                },
+            };
+            let state = rocket.state::<Arc<ApiState>>().unwrap().clone();
+
+            let res = rocket.launch().await;
+
+            // Requests are done by now, nothing can modify the state anymore.
+            state.shutdown().await;
+
+            if let Err(err) = res {
+                tracing::error!("Rocket failed: {:?}", err);
+                std::process::exit(1);
+            }
+        },
+    }
 }
 
 
 #[post("/login", format = "application/json", data = "<request>")]
 async fn login(
-    state: &State<ApiState>,
+    state: &State<Arc<ApiState>>,
+    client: ClientAddr,
     request: Json<LoginRequest>,
 ) -> Result<Json<LoginReply>, status::Forbidden<()>> {
     let status_forbidden = || status::Forbidden::<()>(None);
 
-    let user_password_info = UserPasswordInfo::from_password( request.password.as_str() );
-    let (user, access_token) = state.user_login(&request.username, user_password_info).await.ok_or_else(status_forbidden)?;
+    tracing::debug!("login: user {:?}, device {:?}/{:?} from {}", request.username, request.id, request.uuid, client);
 
-    let reply = LoginReply {
-        user: UserInfo { 
-            name: user 
-        },
-        access_token,
-    };
+    if let Some(secret) = request.secret.as_deref().filter(|secret| !secret.is_empty()) {
+        let (user, access_token) = state
+            .user_login_verify(secret, request.code(), &request.id, &request.uuid, client.ip)
+            .await
+            .ok_or_else(status_forbidden)?;
 
-    tracing::debug!("login: {:?}", request);
+        return Ok(Json(LoginReply::access_token(state.user_info(user).await, access_token)));
+    }
 
-    state.check_maintenance().await;
+    let user_password_info = UserPasswordInfo::from_password( request.password.as_str() );
+    let outcome = state
+        .user_login(&request.username, user_password_info, &request.id, &request.uuid, client.ip)
+        .await
+        .ok_or_else(status_forbidden)?;
+
+    let reply = match outcome {
+        LoginOutcome::LoggedIn(user, access_token) => LoginReply::access_token(state.user_info(user).await, access_token),
+        LoginOutcome::TfaRequired(secret) => LoginReply::tfa_check(secret),
+        LoginOutcome::EmailCheckRequired(user, secret) => LoginReply::email_check(state.user_info(user).await, secret),
+    };
 
     Ok(Json(reply))
 }
 
 #[post("/ab/get", format = "application/json")]
 async fn ab_get(
-    state: &State<ApiState>,
+    state: &State<Arc<ApiState>>,
     user: AuthenticatedUser,
-) -> Result<Json<AbGetResponse>, status::Forbidden<()>> {
+    conditions: Conditions,
+) -> Result<Conditional<Json<AbGetResponse>>, status::Forbidden<()>> {
     tracing::debug!("ab get");
 
-    let abi = state
+    let snapshot = state
         .get_user_address_book(user.user_id)
         .await
-        .unwrap_or_else(|| AddressBook::empty());
+        .unwrap_or_else(|| AddressBookSnapshot::new(AddressBook::empty(), 0));
+
+    if conditions.not_modified(&snapshot.etag, snapshot.updated_at) {
+        tracing::debug!("ab get: not modified");
+        return Ok(Conditional { inner: None, etag: snapshot.etag, updated_at: snapshot.updated_at });
+    }
 
     let reply = AbGetResponse {
         error: false,
-        updated_at: "now".to_string(),
-        data: abi.ab
+        updated_at: httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(snapshot.updated_at)),
+        data: snapshot.address_book.to_json()
     };
 
-    state.check_maintenance().await;
-
     tracing::debug!("ab get reply: {:?}", Json(&reply));
-    Ok(Json(reply))
+    Ok(Conditional { inner: Some(Json(reply)), etag: snapshot.etag, updated_at: snapshot.updated_at })
 }
 
 #[post("/ab", format = "application/json", data = "<request>")]
 async fn ab(
-    state: &State<ApiState>,
+    state: &State<Arc<ApiState>>,
     user: AuthenticatedUser,
+    conditions: Conditions,
     request: Json<AbRequest>,
-) -> Result<(), status::Forbidden<()>> {
+) -> Result<Conditional<()>, status::Custom<Json<ErrorReply>>> {
     tracing::debug!("ab: {:?}", request);
 
-    let ab = request.data.clone();
+    let ab = unwrap_or_return!(
+        AddressBook::from_json(&request.data)
+        .map_err(|err| Err(status::Custom(
+            Status::BadRequest,
+            Json(ErrorReply { error: format!("Malformed address book: {}", err) }),
+        )))
+    );
 
     tracing::debug!("new ab: {:?}", &ab);
 
-    let ab = AddressBook {
-        ab
+    let snapshot = unwrap_or_return!(
+        state
+        .set_user_address_book(user.user_id, ab, conditions.if_match.as_deref())
+        .await
+        .map_err(|err| Err(address_book_error_reply(err)))
+    );
+
+    Ok(Conditional { inner: Some(()), etag: snapshot.etag, updated_at: snapshot.updated_at })
+}
+
+#[get("/admin/users/<username>/ab/revisions")]
+async fn admin_ab_revisions(
+    state: &State<Arc<ApiState>>,
+    admin: AdminUser,
+    username: &str,
+) -> Result<Json<Vec<AbRevisionInfo>>, status::NotFound<()>> {
+    tracing::debug!("ab revisions of {:?} by user {}", username, admin.user.user_id);
+
+    let book_id = unwrap_or_return!(
+        state
+        .find_personal_book_id(username)
+        .await
+        .ok_or(Err(status::NotFound(())))
+    );
+
+    let revisions = unwrap_or_return!(
+        state
+        .list_address_book_revisions(book_id)
+        .await
+        .ok_or(Err(status::NotFound(())))
+    );
+
+    Ok(Json(revisions))
+}
+
+#[get("/admin/users/<username>/ab/revisions/<from>/diff/<to>")]
+async fn admin_ab_diff(
+    state: &State<Arc<ApiState>>,
+    admin: AdminUser,
+    username: &str,
+    from: i64,
+    to: i64,
+) -> Result<Json<AbDiff>, status::NotFound<()>> {
+    tracing::debug!("ab diff of {:?} {}..{} by user {}", username, from, to, admin.user.user_id);
+
+    let book_id = unwrap_or_return!(
+        state
+        .find_personal_book_id(username)
+        .await
+        .ok_or(Err(status::NotFound(())))
+    );
+
+    let (from, to) = unwrap_or_return!(
+        state
+        .get_address_book_revision(book_id, from)
+        .await
+        .zip(state.get_address_book_revision(book_id, to).await)
+        .ok_or(Err(status::NotFound(())))
+    );
+
+    Ok(Json(AddressBook::diff(&from, &to)))
+}
+
+#[post("/admin/users/<username>/ab/revisions/<revision_id>/restore")]
+async fn admin_ab_restore(
+    state: &State<Arc<ApiState>>,
+    admin: AdminUser,
+    username: &str,
+    revision_id: i64,
+) -> Result<Conditional<()>, status::NotFound<()>> {
+    tracing::info!("ab of {:?} restored to revision {} by user {}", username, revision_id, admin.user.user_id);
+
+    let book_id = unwrap_or_return!(
+        state
+        .find_personal_book_id(username)
+        .await
+        .ok_or(Err(status::NotFound(())))
+    );
+
+    let snapshot = unwrap_or_return!(
+        state
+        .restore_address_book_revision(book_id, revision_id)
+        .await
+        .ok_or(Err(status::NotFound(())))
+    );
+
+    Ok(Conditional { inner: Some(()), etag: snapshot.etag, updated_at: snapshot.updated_at })
+}
+
+/// The book `guid` refers to, if the user has at least `rule` access to it.
+async fn find_address_book(state: &ApiState, user: &AuthenticatedUser, guid: &str, rule: AbRule) -> Result<BookId, status::Custom<Json<ErrorReply>>> {
+    let (book_id, user_rule) = state
+        .find_address_book(user.user_id, guid)
+        .await
+        .ok_or_else(|| error_reply(Status::NotFound, "Address book not found"))?;
+
+    if user_rule < rule {
+        return Err(error_reply(Status::Forbidden, "No write access to the address book"));
+    }
+
+    Ok(book_id)
+}
+
+#[post("/ab/shared/profiles")]
+async fn ab_shared_profiles(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+    pagination: Pagination,
+) -> Result<Json<AbPageReply<AbProfile>>, status::Custom<Json<ErrorReply>>> {
+    tracing::debug!("ab shared profiles of user {}: {:?}", user.user_id, pagination);
+
+    let books = state
+        .list_shared_address_books(user.user_id)
+        .await
+        .ok_or_else(|| error_reply(Status::InternalServerError, "Failed to list address books"))?;
+
+    let profiles = books
+        .into_iter()
+        .map(|book| AbProfile {
+            guid: book.guid,
+            name: book.name,
+            owner: String::new(),
+            note: book.note,
+            rule: book.rule.unwrap_or(AbRule::Read) as i64,
+        })
+        .collect();
+
+    Ok(Json(pagination.page(profiles)))
+}
+
+#[post("/ab/peers?<ab>")]
+async fn ab_peers(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+    pagination: Pagination,
+    ab: &str,
+) -> Result<Json<AbPageReply<AbPeer>>, status::Custom<Json<ErrorReply>>> {
+    tracing::debug!("ab peers of {:?} for user {}: {:?}", ab, user.user_id, pagination);
+
+    let book_id = find_address_book(state, &user, ab, AbRule::Read).await?;
+
+    let snapshot = state
+        .get_address_book(book_id)
+        .await
+        .ok_or_else(|| error_reply(Status::InternalServerError, "Address book is unavailable"))?;
+
+    Ok(Json(pagination.page(snapshot.address_book.ab.peers)))
+}
+
+#[post("/ab/tags/<guid>")]
+async fn ab_tags(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+    guid: &str,
+) -> Result<Json<Vec<AbTag>>, status::Custom<Json<ErrorReply>>> {
+    tracing::debug!("ab tags of {:?} for user {}", guid, user.user_id);
+
+    let book_id = find_address_book(state, &user, guid, AbRule::Read).await?;
+
+    let snapshot = state
+        .get_address_book(book_id)
+        .await
+        .ok_or_else(|| error_reply(Status::InternalServerError, "Address book is unavailable"))?;
+
+    Ok(Json(snapshot.address_book.tags()))
+}
+
+#[post("/ab/settings")]
+async fn ab_settings(
+    _user: AuthenticatedUser,
+) -> Json<AbSettingsReply> {
+    Json(AbSettingsReply { max_peer_one_ab: 0 })
+}
+
+#[post("/ab/personal")]
+async fn ab_personal(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+) -> Result<Json<AbPersonalReply>, status::Custom<Json<ErrorReply>>> {
+    tracing::debug!("ab personal of user {}", user.user_id);
+
+    let (_, guid) = state
+        .personal_book(user.user_id)
+        .await
+        .ok_or_else(|| error_reply(Status::InternalServerError, "Address book is unavailable"))?;
+
+    Ok(Json(AbPersonalReply { guid }))
+}
+
+#[post("/ab/peer/add/<guid>", format = "application/json", data = "<request>")]
+async fn ab_peer_add(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+    guid: &str,
+    request: Json<AbPeer>,
+) -> Result<(), status::Custom<Json<ErrorReply>>> {
+    tracing::debug!("ab peer add to {:?} by user {}: {:?}", guid, user.user_id, request);
+
+    let book_id = find_address_book(state, &user, guid, AbRule::ReadWrite).await?;
+
+    state
+        .edit_address_book(book_id, |address_book| ab_edit::add_peer(&mut address_book.ab, request.into_inner()))
+        .await
+        .map_err(address_book_error_reply)?;
+
+    Ok(())
+}
+
+/// Only the fields in the request change.
+#[put("/ab/peer/update/<guid>", format = "application/json", data = "<request>")]
+async fn ab_peer_update(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+    guid: &str,
+    request: Json<Map<String, Value>>,
+) -> Result<(), status::Custom<Json<ErrorReply>>> {
+    tracing::debug!("ab peer update in {:?} by user {}: {:?}", guid, user.user_id, request);
+
+    let book_id = find_address_book(state, &user, guid, AbRule::ReadWrite).await?;
+
+    state
+        .edit_address_book(book_id, |address_book| ab_edit::update_peer(&mut address_book.ab, request.into_inner()))
+        .await
+        .map_err(address_book_error_reply)?;
+
+    Ok(())
+}
+
+/// The request is a list of peer ids.
+#[delete("/ab/peer/<guid>", format = "application/json", data = "<request>")]
+async fn ab_peer_delete(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+    guid: &str,
+    request: Json<Vec<String>>,
+) -> Result<(), status::Custom<Json<ErrorReply>>> {
+    tracing::debug!("ab peer delete from {:?} by user {}: {:?}", guid, user.user_id, request);
+
+    let book_id = find_address_book(state, &user, guid, AbRule::ReadWrite).await?;
+
+    state
+        .edit_address_book(book_id, |address_book| {
+            ab_edit::delete_peers(&mut address_book.ab, &request);
+            Ok(())
+        })
+        .await
+        .map_err(address_book_error_reply)?;
+
+    Ok(())
+}
+
+#[post("/ab/tag/add/<guid>", format = "application/json", data = "<request>")]
+async fn ab_tag_add(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+    guid: &str,
+    request: Json<AbTag>,
+) -> Result<(), status::Custom<Json<ErrorReply>>> {
+    tracing::debug!("ab tag add to {:?} by user {}: {:?}", guid, user.user_id, request);
+
+    let book_id = find_address_book(state, &user, guid, AbRule::ReadWrite).await?;
+
+    state
+        .edit_address_book(book_id, |address_book| ab_edit::add_tag(&mut address_book.ab, request.into_inner()))
+        .await
+        .map_err(address_book_error_reply)?;
+
+    Ok(())
+}
+
+#[put("/ab/tag/rename/<guid>", format = "application/json", data = "<request>")]
+async fn ab_tag_rename(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+    guid: &str,
+    request: Json<AbTagRenameRequest>,
+) -> Result<(), status::Custom<Json<ErrorReply>>> {
+    tracing::debug!("ab tag rename in {:?} by user {}: {:?}", guid, user.user_id, request);
+
+    let book_id = find_address_book(state, &user, guid, AbRule::ReadWrite).await?;
+
+    state
+        .edit_address_book(book_id, |address_book| ab_edit::rename_tag(&mut address_book.ab, &request.old, &request.new))
+        .await
+        .map_err(address_book_error_reply)?;
+
+    Ok(())
+}
+
+/// Changes the color of a tag.
+#[put("/ab/tag/update/<guid>", format = "application/json", data = "<request>")]
+async fn ab_tag_update(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+    guid: &str,
+    request: Json<AbTag>,
+) -> Result<(), status::Custom<Json<ErrorReply>>> {
+    tracing::debug!("ab tag update in {:?} by user {}: {:?}", guid, user.user_id, request);
+
+    let book_id = find_address_book(state, &user, guid, AbRule::ReadWrite).await?;
+
+    state
+        .edit_address_book(book_id, |address_book| ab_edit::update_tag(&mut address_book.ab, request.into_inner()))
+        .await
+        .map_err(address_book_error_reply)?;
+
+    Ok(())
+}
+
+/// The request is a list of tag names.
+#[delete("/ab/tag/<guid>", format = "application/json", data = "<request>")]
+async fn ab_tag_delete(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+    guid: &str,
+    request: Json<Vec<String>>,
+) -> Result<(), status::Custom<Json<ErrorReply>>> {
+    tracing::debug!("ab tag delete from {:?} by user {}: {:?}", guid, user.user_id, request);
+
+    let book_id = find_address_book(state, &user, guid, AbRule::ReadWrite).await?;
+
+    state
+        .edit_address_book(book_id, |address_book| {
+            ab_edit::delete_tags(&mut address_book.ab, &request);
+            Ok(())
+        })
+        .await
+        .map_err(address_book_error_reply)?;
+
+    Ok(())
+}
+
+#[get("/ab/export")]
+async fn ab_export(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+    query: AbTransferQuery,
+) -> Result<AbExport, status::Custom<Json<ErrorReply>>> {
+    tracing::debug!("ab export of user {}: {:?}", user.user_id, query);
+
+    let snapshot = state
+        .get_user_address_book(user.user_id)
+        .await
+        .ok_or_else(|| error_reply(Status::InternalServerError, "Address book is unavailable"))?;
+
+    let data = snapshot.address_book
+        .export(query.format)
+        .ok_or_else(|| error_reply(Status::InternalServerError, "Failed to export address book"))?;
+
+    Ok(AbExport { format: query.format, data })
+}
+
+#[post("/ab/import", data = "<data>")]
+async fn ab_import(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+    query: AbTransferQuery,
+    data: String,
+) -> Result<Json<AbImportReply>, status::Custom<Json<AbImportErrorReply>>> {
+    tracing::debug!("ab import of user {}: {:?}, {} bytes", user.user_id, query, data.len());
+
+    let import_error = |status: Status, error: &str, rows: Vec<AbImportRowError>| {
+        status::Custom(status, Json(AbImportErrorReply { error: error.to_string(), rows }))
     };
 
-    let _ = unwrap_or_return!(
+    let snapshot = state
+        .get_user_address_book(user.user_id)
+        .await
+        .ok_or_else(|| import_error(Status::InternalServerError, "Address book is unavailable", vec![]))?;
+
+    let mut address_book = snapshot.address_book;
+    let reply = address_book
+        .import(&data, query.format, query.mode)
+        .map_err(|rows| import_error(Status::BadRequest, "Nothing imported, some rows are invalid", rows))?;
+
+    state
+        .set_user_address_book(user.user_id, address_book, Some(&snapshot.etag))
+        .await
+        .map_err(|err| {
+            let status::Custom(status, Json(reply)) = address_book_error_reply(err);
+            import_error(status, &reply.error, vec![])
+        })?;
+
+    tracing::info!("ab import of user {}: {:?}", user.user_id, reply);
+    Ok(Json(reply))
+}
+
+#[get("/admin/users/<username>/sessions")]
+async fn admin_user_sessions(
+    state: &State<Arc<ApiState>>,
+    admin: AdminUser,
+    username: &str,
+) -> Result<Json<Vec<DeviceSessions>>, status::NotFound<()>> {
+    tracing::debug!("sessions of {:?} by user {}", username, admin.user.user_id);
+
+    let user_id = unwrap_or_return!(
         state
-        .set_user_address_book(user.user_id, ab)
+        .find_user_id(username)
         .await
-        .ok_or(Err(status::Forbidden::<()>(None)))
+        .ok_or(Err(status::NotFound(())))
     );
 
-    state.check_maintenance().await;
+    Ok(Json(state.list_user_sessions(user_id).await))
+}
+
+/// Sessions of the user making the request.
+#[get("/sessions")]
+async fn sessions(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+) -> Json<UserSessionsReply> {
+    tracing::debug!("sessions of user {}", user.user_id);
+
+    Json(UserSessionsReply {
+        current_session_id: user.session_id,
+        devices: state.list_user_sessions(user.user_id).await,
+    })
+}
+
+#[delete("/sessions/<session_id>")]
+async fn revoke_session(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+    session_id: SessionId,
+) -> Result<(), status::NotFound<()>> {
+    tracing::info!("session {} revoked by user {}", session_id, user.user_id);
+
+    if !state.revoke_user_session(user.user_id, session_id).await {
+        return Err(status::NotFound(()));
+    }
 
     Ok(())
 }
 
+/// Log out everywhere else.
+#[delete("/sessions")]
+async fn revoke_other_sessions(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+) -> Json<RevokeSessionsReply> {
+    let revoked = state.revoke_other_sessions(&user).await;
+
+    tracing::info!("{} other sessions revoked by user {}", revoked, user.user_id);
+    Json(RevokeSessionsReply { revoked })
+}
+
+/// Start enrolment in two-factor authentication. It is enabled once a code confirms the authenticator got the secret.
+#[post("/tfa/setup")]
+async fn tfa_setup(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+) -> Result<Json<TfaSetupReply>, status::Custom<Json<ErrorReply>>> {
+    let username = state.get_current_user_name(&user).await.unwrap_or_default();
+
+    let secret = unwrap_or_return!(
+        state
+        .tfa_setup(user.user_id)
+        .await
+        .map_err(|err| Err(tfa_error_reply(err)))
+    );
+
+    let uri = totp::otpauth_uri(&username, &secret);
+    Ok(Json(TfaSetupReply { secret, uri }))
+}
+
+#[post("/tfa/enable", format = "application/json", data = "<request>")]
+async fn tfa_enable(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+    request: Json<TfaCodeRequest>,
+) -> Result<Json<TfaEnableReply>, status::Custom<Json<ErrorReply>>> {
+    let recovery_codes = unwrap_or_return!(
+        state
+        .tfa_enable(user.user_id, &request.code)
+        .await
+        .map_err(|err| Err(tfa_error_reply(err)))
+    );
+
+    tracing::info!("two-factor authentication enabled by user {}", user.user_id);
+    Ok(Json(TfaEnableReply { recovery_codes }))
+}
+
+/// Takes a TOTP code or a recovery code.
+#[post("/tfa/disable", format = "application/json", data = "<request>")]
+async fn tfa_disable(
+    state: &State<Arc<ApiState>>,
+    user: AuthenticatedUser,
+    request: Json<TfaCodeRequest>,
+) -> Result<(), status::Custom<Json<ErrorReply>>> {
+    unwrap_or_return!(
+        state
+        .tfa_disable(user.user_id, &request.code)
+        .await
+        .map_err(|err| Err(tfa_error_reply(err)))
+    );
+
+    tracing::info!("two-factor authentication disabled by user {}", user.user_id);
+    Ok(())
+}
+
 #[post("/currentUser", format = "application/json", data = "<request>")]
 async fn current_user(
-    state: &State<ApiState>,
+    state: &State<Arc<ApiState>>,
     user: AuthenticatedUser,
     request: Json<CurrentUserRequest>,
 ) -> Result<Json<CurrentUserResponse>, status::Forbidden<()>> {
     tracing::debug!("current_user authenticated request: {:?}", request);
 
+    if !user.is_device(&request.id, &request.uuid) {
+        tracing::warn!("current_user: session {} of user {} used by device {:?}/{:?}", user.session_id, user.user_id, request.id, request.uuid);
+        return Err(status::Forbidden::<()>(None));
+    }
+
     let username = unwrap_or_return!(
         state
         .get_current_user_name(&user)
@@ -178,33 +886,186 @@ async fn current_user(
 
     let reply = CurrentUserResponse {
         error: false,
-        data: UserInfo { 
-            name: username 
-        }
+        data: state.user_info(username).await,
     };
 
     tracing::debug!("current_user reply: {:?}", reply);
     Ok(Json(reply))
 }
 
-#[post("/audit", format = "application/json", data = "<request>")]
+/// Queue an audit event built by `new_event` from the posted request, keeping the request verbatim as payload.
+/// Requests over the `audit` limit are refused, so one client can't fill the audit log with huge payloads.
+async fn add_audit<R: DeserializeOwned + std::fmt::Debug>(
+    state: &ApiState,
+    client: ClientAddr,
+    user: Option<AuthenticatedUser>,
+    limits: &Limits,
+    data: Data<'_>,
+    new_event: fn(u64, IpAddr, Option<UserId>, R, String) -> AuditEvent,
+) -> Result<(), status::Custom<()>> {
+    let limit = limits.get("audit").unwrap_or(AUDIT_PAYLOAD_LIMIT);
+    let payload = unwrap_or_return!(
+        data.open(limit).into_string().await
+        .map_err(|_| Err(status::Custom(Status::BadRequest, ())))
+    );
+    if !payload.is_complete() {
+        return Err(status::Custom(Status::PayloadTooLarge, ()));
+    }
+    let payload: Value = unwrap_or_return!(
+        serde_json::from_str(&payload)
+        .map_err(|_| Err(status::Custom(Status::BadRequest, ())))
+    );
+
+    let audit_request: R = unwrap_or_return!(
+        serde_json::from_value(payload.clone())
+        .map_err(|_| Err(status::Custom(Status::BadRequest, ())))
+    );
+
+    tracing::debug!("audit from {}: {:?}", client, audit_request);
+
+    let event = new_event(
+        secs_from_epoch(),
+        client.ip,
+        user.map(|user| user.user_id),
+        audit_request,
+        payload.to_string(),
+    );
+
+    state.add_audit_event(event).await;
+
+    Ok(())
+}
+
+#[post("/audit", format = "application/json", data = "<data>")]
 async fn audit(
-    state: &State<ApiState>,
-    request: Json<AuditRequest>,
-) {
-    tracing::debug!("audit: {:?}", request);
-    state.check_maintenance().await;
+    state: &State<Arc<ApiState>>,
+    client: ClientAddr,
+    user: Option<AuthenticatedUser>,
+    limits: &Limits,
+    data: Data<'_>,
+) -> Result<(), status::Custom<()>> {
+    add_audit::<AuditRequest>(state, client, user, limits, data, AuditEvent::new).await
+}
+
+#[post("/audit/conn", format = "application/json", data = "<data>")]
+async fn audit_conn(
+    state: &State<Arc<ApiState>>,
+    client: ClientAddr,
+    user: Option<AuthenticatedUser>,
+    limits: &Limits,
+    data: Data<'_>,
+) -> Result<(), status::Custom<()>> {
+    add_audit::<ConnAuditRequest>(state, client, user, limits, data, AuditEvent::from_conn).await
+}
+
+#[post("/audit/file", format = "application/json", data = "<data>")]
+async fn audit_file(
+    state: &State<Arc<ApiState>>,
+    client: ClientAddr,
+    user: Option<AuthenticatedUser>,
+    limits: &Limits,
+    data: Data<'_>,
+) -> Result<(), status::Custom<()>> {
+    add_audit::<FileAuditRequest>(state, client, user, limits, data, AuditEvent::from_file).await
+}
+
+#[post("/audit/alarm", format = "application/json", data = "<data>")]
+async fn audit_alarm(
+    state: &State<Arc<ApiState>>,
+    client: ClientAddr,
+    user: Option<AuthenticatedUser>,
+    limits: &Limits,
+    data: Data<'_>,
+) -> Result<(), status::Custom<()>> {
+    add_audit::<AlarmAuditRequest>(state, client, user, limits, data, AuditEvent::from_alarm).await
+}
+
+type AuditPage<T> = Result<Either<Json<AuditPageReply<T>>, AuditPageCsv>, status::Custom<()>>;
+
+/// The filter for a query, asking for one extra row which tells whether there is a next page.
+fn audit_filter(query: AuditQuery) -> (AuditFilter, u32, AuditFormat) {
+    let limit = query.limit.unwrap_or(AUDIT_QUERY_DEFAULT_LIMIT).clamp(1, AUDIT_QUERY_MAX_LIMIT);
+
+    let filter = AuditFilter {
+        kind: query.kind,
+        username: query.user,
+        peer_id: query.peer_id,
+        uuid: query.uuid,
+        action: query.action,
+        ip: query.ip,
+        from: query.from,
+        to: query.to,
+        cursor: query.cursor,
+        limit: limit + 1,
+    };
+
+    (filter, limit, query.format)
+}
+
+fn audit_page<T: AuditRecord>(records: Option<Vec<T>>, limit: u32, format: AuditFormat) -> AuditPage<T> {
+    let mut records = unwrap_or_return!(
+        records
+        .ok_or(Err(status::Custom(Status::InternalServerError, ())))
+    );
+
+    let next_cursor = if records.len() > limit as usize {
+        records.truncate(limit as usize);
+        records.last().map(AuditRecord::cursor)
+    } else {
+        None
+    };
+
+    match format {
+        AuditFormat::Json => Ok(Either::Left(Json(AuditPageReply { data: records, next_cursor }))),
+        AuditFormat::Csv => {
+            let data = unwrap_or_return!(
+                to_csv(&records)
+                .ok_or(Err(status::Custom(Status::InternalServerError, ())))
+            );
+
+            Ok(Either::Right(AuditPageCsv { data, next_cursor }))
+        },
+    }
+}
+
+#[get("/audit")]
+async fn audit_query(
+    state: &State<Arc<ApiState>>,
+    admin: AdminUser,
+    query: AuditQuery,
+) -> AuditPage<AuditEventRecord> {
+    tracing::debug!("audit query by user {}: {:?}", admin.user.user_id, query);
+
+    let (filter, limit, format) = audit_filter(query);
+    audit_page(state.find_audit_events(&filter).await, limit, format)
+}
+
+#[get("/audit/sessions")]
+async fn audit_sessions(
+    state: &State<Arc<ApiState>>,
+    admin: AdminUser,
+    query: AuditQuery,
+) -> AuditPage<ConnSessionRecord> {
+    tracing::debug!("audit sessions query by user {}: {:?}", admin.user.user_id, query);
+
+    let (filter, limit, format) = audit_filter(query);
+    audit_page(state.find_conn_sessions(&filter).await, limit, format)
 }
 
 #[post("/logout", format = "application/json", data = "<request>")]
 async fn logout(
-    state: &State<ApiState>,
+    state: &State<Arc<ApiState>>,
     user: AuthenticatedUser,
     request: Json<CurrentUserRequest>,
 ) -> Result<Json<LogoutReply>, status::Forbidden<()>> {
     tracing::debug!("logout: {:?}", request);
 
-    let _ = unwrap_or_return!(
+    if !user.is_device(&request.id, &request.uuid) {
+        tracing::warn!("logout: session {} of user {} used by device {:?}/{:?}", user.session_id, user.user_id, request.id, request.uuid);
+        return Err(status::Forbidden::<()>(None));
+    }
+
+    unwrap_or_return!(
         state
         .user_logout(&user)
         .await
@@ -215,8 +1076,6 @@ async fn logout(
         data: String::new()
     };
 
-    state.check_maintenance().await;
-
     Ok(Json(reply))
 }
 
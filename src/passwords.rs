@@ -0,0 +1,112 @@
+use argon2::{
+    Argon2, Algorithm,
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
+};
+use subtle::ConstantTimeEq;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordCheck {
+    /// Password doesn't match the stored one.
+    Invalid,
+    /// Password matches and the stored hash is up to date.
+    Valid,
+    /// Password matches, but the stored value is plaintext or uses an outdated scheme.
+    ValidNeedsRehash,
+}
+
+/// Hash a password into a PHC-format Argon2id string.
+pub fn hash_password(password: &str) -> Option<String> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .ok()?
+        .to_string();
+
+    Some(hash)
+}
+
+fn is_bcrypt_hash(stored: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| stored.starts_with(prefix))
+}
+
+/// Verify a password against the value stored in the `passwords` table.
+///
+/// Accepts Argon2 PHC strings, bcrypt hashes (for imported users) and legacy plaintext.
+pub fn verify_password(stored: &str, password: &str) -> PasswordCheck {
+    if is_bcrypt_hash(stored) {
+        return match bcrypt::verify(password, stored) {
+            Ok(true) => PasswordCheck::ValidNeedsRehash,
+            _ => PasswordCheck::Invalid,
+        };
+    }
+
+    // A plaintext password may start with `$` too, so anything which isn't an Argon2 hash is compared as plaintext.
+    if let Some(hash) = PasswordHash::new(stored).ok().filter(|hash| Algorithm::try_from(hash.algorithm).is_ok()) {
+        if Argon2::default().verify_password(password.as_bytes(), &hash).is_err() {
+            return PasswordCheck::Invalid;
+        }
+
+        if hash.algorithm != Algorithm::Argon2id.ident() {
+            return PasswordCheck::ValidNeedsRehash;
+        }
+
+        return PasswordCheck::Valid;
+    }
+
+    // Legacy plaintext row.
+    if bool::from(stored.as_bytes().ct_eq(password.as_bytes())) {
+        PasswordCheck::ValidNeedsRehash
+    } else {
+        PasswordCheck::Invalid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::{Params, Version};
+
+    fn argon2_hash(algorithm: Algorithm, password: &str) -> String {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::new(algorithm, Version::V0x13, Params::default())
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn argon2id() {
+        let stored = hash_password("secret").unwrap();
+        assert_eq!(verify_password(&stored, "secret"), PasswordCheck::Valid);
+        assert_eq!(verify_password(&stored, "wrong"), PasswordCheck::Invalid);
+    }
+
+    #[test]
+    fn argon2i_needs_rehash() {
+        let stored = argon2_hash(Algorithm::Argon2i, "secret");
+        assert_eq!(verify_password(&stored, "secret"), PasswordCheck::ValidNeedsRehash);
+        assert_eq!(verify_password(&stored, "wrong"), PasswordCheck::Invalid);
+    }
+
+    #[test]
+    fn bcrypt_needs_rehash() {
+        let stored = bcrypt::hash("secret", 4).unwrap();
+        assert_eq!(verify_password(&stored, "secret"), PasswordCheck::ValidNeedsRehash);
+        assert_eq!(verify_password(&stored, "wrong"), PasswordCheck::Invalid);
+    }
+
+    #[test]
+    fn plaintext_needs_rehash() {
+        assert_eq!(verify_password("secret", "secret"), PasswordCheck::ValidNeedsRehash);
+        assert_eq!(verify_password("secret", "wrong"), PasswordCheck::Invalid);
+    }
+
+    #[test]
+    fn plaintext_starting_with_dollar() {
+        for stored in ["$ecret", "$abc$def", "$"] {
+            assert_eq!(verify_password(stored, stored), PasswordCheck::ValidNeedsRehash, "{}", stored);
+            assert_eq!(verify_password(stored, "wrong"), PasswordCheck::Invalid, "{}", stored);
+        }
+    }
+}
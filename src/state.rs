@@ -1,25 +1,50 @@
 use std::{
     default::Default,
-    collections::HashMap,
-    time::SystemTime,
-    sync::atomic::{AtomicU64, Ordering},
+    collections::{HashMap, HashSet, VecDeque, hash_map::Entry},
+    net::IpAddr,
+    time::{SystemTime, Duration, Instant},
+    sync::Arc,
 };
-use tokio::sync::RwLock;
+use tokio::{
+    sync::{RwLock, RwLockWriteGuard, Mutex},
+    task::JoinHandle,
+    time::MissedTickBehavior,
+};
+use rand::{thread_rng, Rng};
+use subtle::ConstantTimeEq;
 use crate::{
     AddressBook,
-    tokens::Token,
-    database::{Database, DatabaseUserPasswordInfo}, bearer::AuthenticatedUser,
+    tokens::{Token, TokenHash},
+    database::{Database, DatabaseBookRecord}, bearer::AuthenticatedUser,
+    auth::AuthChain,
+    audit_log::{AuditEvent, AuditEventRecord, AuditFilter, ConnSessionRecord},
+    config::{SessionsConfig, AddressBooksConfig, LoginConfig, ConflictMode},
+    mail::MailSender,
+    api::{self, AbRevisionInfo, AbRule, DeviceSessions, SessionRecord, etag_list_contains},
+    ab_edit::AbEditError,
+    totp,
 };
 
-pub type SessionId = u64;
+pub type SessionId = i64;
 pub type UserId = i64;
+pub type BookId = i64;
 
 pub struct ApiState {
-    last_maintenance_time: AtomicU64,
-    access_tokens: RwLock<HashMap<Token, AccessTokenInfo>>,
+    access_tokens: RwLock<HashMap<TokenHash, AccessTokenInfo>>,
     sessions: RwLock<SessionsState>,
     users: RwLock<HashMap<UserId, UserInfo>>,
-    address_books: RwLock<HashMap<UserId, AddressBookInfo>>,
+    address_books: RwLock<HashMap<BookId, AddressBookInfo>>,
+    /// Id and guid of personal books, of users with sessions or with their book in the cache.
+    personal_books: RwLock<HashMap<UserId, (BookId, String)>>,
+    audit_events: Mutex<Vec<AuditEvent>>,
+    /// Logins waiting for a second factor, by the hash of the secret handed to the client.
+    pending_logins: Mutex<HashMap<TokenHash, PendingLogin>>,
+    sessions_config: SessionsConfig,
+    address_books_config: AddressBooksConfig,
+    login_config: LoginConfig,
+    /// Set if `login.email_verification` is on.
+    mail_sender: Option<Box<dyn MailSender>>,
+    auth: AuthChain,
     db: Database,
 }
 
@@ -27,11 +52,13 @@ pub struct ApiState {
 pub struct AccessTokenInfo {
     pub session_id: SessionId,
     pub user_id: UserId,
+    /// The device which logged in, the token is bound to it.
+    pub device_id: String,
+    pub device_uuid: String,
 }
 
 #[derive(Debug, Default)]
 struct SessionsState {
-    counter: SessionId,
     sessions: HashMap<SessionId, SessionInfo>,
 }
 
@@ -45,10 +72,54 @@ impl<'s> UserPasswordInfo<'s> {
             password
         }
     }
+}
 
-    fn check( &self, db_password_info: DatabaseUserPasswordInfo ) -> bool {
-        db_password_info.password.eq( self.password )
-    }
+/// A login with a valid password, which needs a code to become a session.
+#[derive(Debug, Clone)]
+struct PendingLogin {
+    user_id: UserId,
+    username: String,
+    device_id: String,
+    device_uuid: String,
+    check: LoginCheck,
+    expires_at: u64,
+    attempts: u32,
+}
+
+#[derive(Debug, Clone)]
+enum LoginCheck {
+    /// A TOTP or recovery code.
+    Tfa,
+    /// The code sent by email.
+    Email(String),
+}
+
+/// A pending login waiting for a TOTP code is dropped after this many seconds.
+const PENDING_LOGIN_TIMEOUT: u64 = 5 * 60;
+/// A pending login is dropped after this many wrong codes.
+const PENDING_LOGIN_MAX_ATTEMPTS: u32 = 5;
+
+pub enum LoginOutcome {
+    /// Username and access token of the new session.
+    LoggedIn(String, Token),
+    /// The user has two-factor authentication enabled: the client sends a code back with this secret.
+    TfaRequired(String),
+    /// A code was sent to the user by email, for a login from an unknown device: username, and the secret
+    /// to send the code back with.
+    EmailCheckRequired(String, String),
+}
+
+/// Digits of codes sent by email.
+const EMAIL_CODE_DIGITS: usize = 6;
+
+#[derive(Debug)]
+pub enum TfaError {
+    AlreadyEnabled,
+    NotEnabled,
+    /// Enabling without a secret from enrolment first.
+    NotEnrolled,
+    InvalidCode,
+    Unavailable,
 }
 
 #[derive(Debug, Default)]
@@ -57,212 +128,1047 @@ struct UserInfo {
     username: String,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 struct SessionInfo {
-    #[allow(dead_code)]
     user_id: UserId,
+    token_hash: TokenHash,
+    device_id: String,
+    device_uuid: String,
+    ip: String,
+    created_at: u64,
+    last_seen_at: u64,
+    modified: bool,
+}
+
+/// An address book together with what conditional requests need to know about it.
+#[derive(Debug, Clone)]
+pub struct AddressBookSnapshot {
+    pub address_book: AddressBook,
+    /// Last modification, seconds since the epoch.
+    pub updated_at: u64,
+    pub etag: String,
+}
+
+impl AddressBookSnapshot {
+    pub fn new(address_book: AddressBook, updated_at: u64) -> Self {
+        let etag = address_book.etag();
+        Self { address_book, updated_at, etag }
+    }
+}
+
+#[derive(Debug)]
+pub enum AddressBookError {
+    /// The book couldn't be loaded or created.
+    Unavailable,
+    /// An upload based on an outdated version of the book, which couldn't be merged.
+    Conflict,
+    /// An incremental change which doesn't apply to the book.
+    Rejected(AbEditError),
 }
 
 #[derive(Debug, Clone)]
 pub struct AddressBookInfo {
     modified: bool,
     remove_after_flush: bool,
-    pub address_book: AddressBook,
+    last_access: Instant,
+    pub snapshot: AddressBookSnapshot,
+    /// Previous versions, oldest first, to merge uploads based on them.
+    history: VecDeque<AddressBookSnapshot>,
 }
 
-const MAINTENANCE_INTERVAL_IN_SECS: u64 = 60;
+impl AddressBookInfo {
+    /// Make `address_book` the current version, keeping up to `merge_history` previous ones.
+    fn update(&mut self, address_book: AddressBook, merge_history: usize) {
+        if self.snapshot.address_book == address_book {
+            return;
+        }
 
-fn secs_from_epoch() -> u64 {
+        let previous = std::mem::replace(&mut self.snapshot, AddressBookSnapshot::new(address_book, secs_from_epoch()));
+        self.history.push_back(previous);
+        while self.history.len() > merge_history {
+            self.history.pop_front();
+        }
+        self.modified = true;
+    }
+}
+
+/// Last use of a session is updated at most this often, in seconds.
+const LAST_SEEN_RESOLUTION: u64 = 30;
+
+/// Audit events are written when this many are queued, or on the next maintenance run.
+const AUDIT_EVENTS_BATCH_SIZE: usize = 100;
+/// Queued audit events kept while the database fails to take them; older ones are dropped.
+const AUDIT_EVENTS_MAX_QUEUED: usize = 10_000;
+
+/// Drop the oldest events beyond `AUDIT_EVENTS_MAX_QUEUED`.
+fn limit_audit_events(events: &mut Vec<AuditEvent>) {
+    if events.len() > AUDIT_EVENTS_MAX_QUEUED {
+        let excess = events.len() - AUDIT_EVENTS_MAX_QUEUED;
+        events.drain(..excess);
+        tracing::error!("Dropped {} audit events which couldn't be written", excess);
+    }
+}
+
+pub fn secs_from_epoch() -> u64 {
     SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
 }
 
 impl ApiState {
-    pub fn new( db: Database ) -> Self {
+    pub fn new( db: Database, sessions_config: SessionsConfig, address_books_config: AddressBooksConfig, login_config: LoginConfig, mail_sender: Option<Box<dyn MailSender>>, auth: AuthChain ) -> Self {
         Self { 
-            last_maintenance_time: AtomicU64::new(0),
             access_tokens: Default::default(), 
             sessions: Default::default(), 
             users: Default::default(), 
             address_books: Default::default(), 
+            personal_books: Default::default(),
+            audit_events: Default::default(),
+            pending_logins: Default::default(),
+            sessions_config,
+            address_books_config,
+            login_config,
+            mail_sender,
+            auth,
             db 
         }
     }
 
-    pub async fn maintenance_flush_address_books(&self) {
+    /// Write modified address books to the database. Returns the number of books written.
+    pub async fn maintenance_flush_address_books(&self) -> usize {
         let mut state_address_books = self.address_books.write().await;
-        
-        let mut values: Vec<(UserId, AddressBook)> = vec![];
 
-        for (user_id, address_book_info) in state_address_books.iter_mut() {
-            if !address_book_info.modified {
-                continue;
-            }
+        let values: Vec<(BookId, AddressBook, u64)> = state_address_books
+            .iter()
+            .filter(|(_, address_book_info)| address_book_info.modified)
+            .map(|(book_id, address_book_info)| {
+                let snapshot = &address_book_info.snapshot;
+                (*book_id, snapshot.address_book.clone(), snapshot.updated_at)
+            })
+            .collect();
+
+        if values.is_empty() {
+            return 0;
+        }
+
+        let values_count = values.len();
 
-            values.push((*user_id, address_book_info.address_book.clone()));
+        tracing::debug!("Need to update_address_books");
+        if self.db.update_address_books(values).await.is_none() {
+            // Keep them marked as modified, so the next run retries.
+            tracing::error!("Failed to write {} address books", values_count);
+            return 0;
+        }
+
+        for address_book_info in state_address_books.values_mut() {
             address_book_info.modified = false;
         }
 
-        if !values.is_empty() {
-            tracing::debug!("Need to update_address_books");
-            self.db.update_address_books(values).await;
+        values_count
+    }
+
+    /// Drop clean address books of logged out users and keep the cache within its limit.
+    /// Shared books are only bounded by `cache_limit`.
+    pub async fn maintenance_evict_address_books(&self) {
+        let state_users = self.users.read().await;
+        let mut state_personal_books = self.personal_books.write().await;
+        let mut state_address_books = self.address_books.write().await;
+
+        // Logouts only mark books cached at the time; books loaded later, e.g. by admins, are marked here.
+        for (user_id, (book_id, _)) in state_personal_books.iter() {
+            if let Some(address_book_info) = state_address_books.get_mut(book_id) {
+                address_book_info.remove_after_flush = !state_users.contains_key(user_id);
+            }
+        }
+
+        let count_before = state_address_books.len();
+
+        state_address_books.retain(|_, address_book_info| {
+            !address_book_info.remove_after_flush || address_book_info.modified
+        });
+
+        state_personal_books.retain(|user_id, (book_id, _)| {
+            state_users.contains_key(user_id) || state_address_books.contains_key(book_id)
+        });
+
+        Self::evict_lru_address_books(&mut state_address_books, self.address_books_config.cache_limit, None);
+
+        let evicted = count_before - state_address_books.len();
+        if evicted > 0 {
+            tracing::debug!("Evicted {} address books, {} left", evicted, state_address_books.len());
+        }
+    }
+
+    /// Evict least recently used clean address books while there are more than `limit` of them.
+    /// Modified books are kept until they are flushed, and so is `keep`, the book being accessed.
+    /// Zero `limit` means no limit.
+    fn evict_lru_address_books(address_books: &mut HashMap<BookId, AddressBookInfo>, limit: usize, keep: Option<BookId>) {
+        if limit == 0 || address_books.len() <= limit {
+            return;
+        }
+
+        let mut candidates: Vec<(Instant, BookId)> = address_books
+            .iter()
+            .filter(|(book_id, address_book_info)| !address_book_info.modified && Some(**book_id) != keep)
+            .map(|(book_id, address_book_info)| (address_book_info.last_access, *book_id))
+            .collect();
+
+        candidates.sort_unstable();
+
+        let excess = address_books.len() - limit;
+        for (_, book_id) in candidates.into_iter().take(excess) {
+            address_books.remove(&book_id);
+        }
+    }
+
+    /// Write queued audit events to the database. Returns the number of events written.
+    pub async fn maintenance_flush_audit_events(&self) -> usize {
+        let mut state_audit_events = self.audit_events.lock().await;
+
+        if state_audit_events.is_empty() {
+            return 0;
+        }
+
+        let events = std::mem::take(&mut *state_audit_events);
+        let events_count = events.len();
+
+        drop(state_audit_events);
+
+        if self.db.insert_audit_events(&events).await.is_none() {
+            tracing::error!("Failed to write {} audit events", events_count);
+
+            // Put them back in front of the events queued meanwhile, so the next run retries.
+            let mut state_audit_events = self.audit_events.lock().await;
+            let newer = std::mem::replace(&mut *state_audit_events, events);
+            state_audit_events.extend(newer);
+            limit_audit_events(&mut state_audit_events);
+            return 0;
+        }
+
+        events_count
+    }
+
+    pub async fn maintenance_flush_sessions(&self) {
+        let state_sessions = self.sessions.read().await;
+
+        let values: Vec<(SessionId, u64)> = state_sessions.sessions
+            .iter()
+            .filter(|(_, session_info)| session_info.modified)
+            .map(|(session_id, session_info)| (*session_id, session_info.last_seen_at))
+            .collect();
+
+        drop(state_sessions);
+
+        if values.is_empty() {
+            return;
+        }
+
+        tracing::debug!("Need to update_sessions_last_seen");
+        if self.db.update_sessions_last_seen(values.clone()).await.is_none() {
+            // Keep them marked as modified, so the next run retries.
+            tracing::error!("Failed to write last use of {} sessions", values.len());
+            return;
+        }
+
+        // Sessions used again meanwhile stay marked as modified.
+        let mut state_sessions = self.sessions.write().await;
+        for (session_id, last_seen_at) in values {
+            if let Some(session_info) = state_sessions.sessions.get_mut(&session_id) {
+                if session_info.last_seen_at == last_seen_at {
+                    session_info.modified = false;
+                }
+            }
+        }
+    }
+
+    pub async fn maintenance_expire_sessions(&self) {
+        let now = secs_from_epoch();
+        let state_sessions = self.sessions.read().await;
+
+        let expired: Vec<SessionId> = state_sessions.sessions
+            .iter()
+            .filter(|(_, session_info)| self.sessions_config.is_expired(now, session_info.created_at, session_info.last_seen_at))
+            .map(|(session_id, _)| *session_id)
+            .collect();
+
+        drop(state_sessions);
+
+        if !expired.is_empty() {
+            tracing::debug!("Expiring {} sessions", expired.len());
+            self.remove_sessions(expired).await;
         }
     }
 
+    /// Drop sessions which were revoked in the database, e.g. by disabling the user from the CLI. Until then
+    /// their tokens stay valid, so a revocation takes up to `maintenance_interval` to reach running servers.
+    pub async fn maintenance_sync_sessions(&self) {
+        // Sessions are stored before they are cached, so every session cached before the query is either in
+        // its result or revoked. Sessions created later are left for the next run.
+        let cached_session_ids: Vec<SessionId> = self.sessions.read().await.sessions.keys().copied().collect();
+
+        let valid_session_ids: HashSet<SessionId> = match self.db.get_valid_session_ids().await {
+            Some(session_ids) => session_ids.into_iter().collect(),
+            None => return,
+        };
+
+        let revoked: Vec<SessionId> = cached_session_ids
+            .into_iter()
+            .filter(|session_id| !valid_session_ids.contains(session_id))
+            .collect();
+
+        if !revoked.is_empty() {
+            tracing::info!("Dropping {} revoked sessions", revoked.len());
+            self.remove_sessions(revoked).await;
+        }
+    }
+
+    pub async fn maintenance_prune_address_book_revisions(&self) {
+        let config = &self.address_books_config;
+
+        let keep = match config.revisions {
+            0 => usize::MAX,
+            revisions => revisions,
+        };
+        let created_before = secs_from_epoch().saturating_sub(match config.revisions_max_age {
+            0 => u64::MAX,
+            max_age => max_age,
+        });
+
+        match self.db.prune_address_book_revisions(keep, created_before).await {
+            Some(0) => {},
+            Some(pruned) => tracing::debug!("Pruned {} address book revisions", pruned),
+            None => tracing::error!("Failed to prune address book revisions"),
+        }
+    }
+
+    pub async fn maintenance_expire_pending_logins(&self) {
+        let now = secs_from_epoch();
+        self.pending_logins.lock().await.retain(|_, pending| pending.expires_at > now);
+    }
+
     pub async fn maintenance(&self) {
+        self.maintenance_sync_sessions().await;
+        self.maintenance_expire_pending_logins().await;
+        self.maintenance_expire_sessions().await;
         self.maintenance_flush_address_books().await;
+        self.maintenance_evict_address_books().await;
+        self.maintenance_prune_address_book_revisions().await;
+        self.maintenance_flush_sessions().await;
+        self.maintenance_flush_audit_events().await;
     }
 
-    pub async fn check_maintenance(&self) {
-        tracing::debug!("check_maintenance...");
+    /// Final flush before the process exits.
+    pub async fn shutdown(&self) {
+        let address_books_count = self.maintenance_flush_address_books().await;
+        self.maintenance_flush_sessions().await;
+        self.maintenance_flush_audit_events().await;
 
-        let now = secs_from_epoch();
-        let last_mt = self.last_maintenance_time.load(Ordering::Relaxed);
+        tracing::info!("Shutdown: {} address books written", address_books_count);
+    }
+
+    /// Reload sessions stored in the database, so tokens issued before a restart stay valid.
+    pub async fn restore_sessions(&self) {
+        let db_sessions = match self.db.load_sessions().await {
+            Some(db_sessions) => db_sessions,
+            None => {
+                tracing::error!("Failed to load sessions");
+                return;
+            }
+        };
+
+        let mut state_access_tokens = self.access_tokens.write().await;
+        let mut state_sessions = self.sessions.write().await;
+        let mut state_users = self.users.write().await;
+
+        let sessions_count = db_sessions.len();
+
+        for db_session in db_sessions {
+            let user_info = state_users.entry(db_session.user_id).or_insert_with(|| UserInfo {
+                sessions_count: 0,
+                username: db_session.username,
+            });
+            user_info.sessions_count += 1;
+
+            let access_token_info = AccessTokenInfo {
+                session_id: db_session.session_id,
+                user_id: db_session.user_id,
+                device_id: db_session.device_id.clone(),
+                device_uuid: db_session.device_uuid.clone(),
+            };
+
+            let session_info = SessionInfo {
+                user_id: db_session.user_id,
+                token_hash: db_session.token_hash,
+                device_id: db_session.device_id,
+                device_uuid: db_session.device_uuid,
+                ip: db_session.ip,
+                created_at: db_session.created_at,
+                last_seen_at: db_session.last_seen_at,
+                modified: false,
+            };
+
+            state_sessions.sessions.insert(db_session.session_id, session_info);
+            state_access_tokens.insert(db_session.token_hash, access_token_info);
+        }
+
+        tracing::info!("Restored {} sessions", sessions_count);
+    }
+
+    /// Run maintenance every `interval` in a background task.
+    pub fn spawn_maintenance(state: Arc<ApiState>, interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            // The first tick completes immediately.
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+                tracing::debug!("maintenance...");
+                state.maintenance().await;
+            }
+        })
+    }
+
+    /// Check the password; if the user has two-factor authentication enabled, or logs in from an unknown device
+    /// and email verification is on, the login waits for a code, see [`Self::user_login_verify`].
+    pub async fn user_login<'s>(&self, username: &str, password_info: UserPasswordInfo<'s>, device_id: &str, device_uuid: &str, ip: IpAddr) -> Option<LoginOutcome> {
+        let user_id = self.check_password(username, password_info).await?;
+
+        let db_totp = self.db.get_user_totp(user_id).await?;
+        if db_totp.enabled {
+            let secret = self.add_pending_login(user_id, username, device_id, device_uuid, LoginCheck::Tfa, PENDING_LOGIN_TIMEOUT).await;
+            return Some(LoginOutcome::TfaRequired(secret));
+        }
+
+        if self.login_config.email_verification && !self.db.is_known_device(user_id, device_id, device_uuid).await? {
+            match self.db.get_user_email(user_id).await? {
+                Some(email) => {
+                    let code = Self::new_email_code();
+                    self.send_email_code(&email, &code, username, device_id).await?;
 
-        if now >= (last_mt + MAINTENANCE_INTERVAL_IN_SECS) {
-            self.last_maintenance_time.store(now, Ordering::Relaxed);
-            tracing::debug!("check_maintenance NOW");
-            self.maintenance().await;
+                    let secret = self.add_pending_login(user_id, username, device_id, device_uuid, LoginCheck::Email(code), self.login_config.verification_code_lifetime).await;
+                    return Some(LoginOutcome::EmailCheckRequired(username.to_string(), secret));
+                },
+                None => tracing::debug!("User {} has no email address, login from an unknown device isn't verified", user_id),
+            }
         }
+
+        let access_token = self.create_session(user_id, username, device_id, device_uuid, ip).await?;
+
+        Some(LoginOutcome::LoggedIn(username.to_string(), access_token))
+    }
+
+    /// Returns the secret the client sends back with the code.
+    async fn add_pending_login(&self, user_id: UserId, username: &str, device_id: &str, device_uuid: &str, check: LoginCheck, timeout: u64) -> String {
+        let secret = Token::new_random();
+        let pending = PendingLogin {
+            user_id,
+            username: username.to_string(),
+            device_id: device_id.to_string(),
+            device_uuid: device_uuid.to_string(),
+            check,
+            expires_at: secs_from_epoch() + timeout,
+            attempts: 0,
+        };
+        self.pending_logins.lock().await.insert(secret.hash(), pending);
+
+        secret.to_base64()
     }
 
-    pub async fn user_login<'s>(&self, username: &String, password_info: UserPasswordInfo<'s>) -> Option<(String, Token)> {
-        let (conn, user_id, db_user_info) = match self.db.find_user_by_name(username.as_str()).await {
-            (conn, Some((user_id, db_user_info))) => (conn, user_id, db_user_info),
-            _ => return None,
+    fn new_email_code() -> String {
+        let mut rng = thread_rng();
+        (0..EMAIL_CODE_DIGITS).map(|_| char::from(b'0' + rng.gen_range(0..10))).collect()
+    }
+
+    async fn send_email_code(&self, email: &str, code: &str, username: &str, device_id: &str) -> Option<()> {
+        let subject = "RustDesk verification code";
+        let body = format!(
+            "Your verification code is {}.\n\nIt was requested to log in as {} from device {}, and is valid for {} minutes. \
+            If it wasn't you, change your password.",
+            code, username, device_id, self.login_config.verification_code_lifetime / 60,
+        );
+
+        let mail_sender = match &self.mail_sender {
+            Some(mail_sender) => mail_sender,
+            None => {
+                tracing::error!("No mail.transport to send verification codes");
+                return None;
+            },
         };
 
-        if !db_user_info.active {
+        if let Err(err) = mail_sender.send(email, subject, &body).await {
+            tracing::error!("Failed to send verification code to {}: {}", email, err);
             return None;
         }
 
-        let (conn, db_password_info) = match self.db.get_user_password(conn, user_id).await {
-            (conn, Some(db_password_info)) => (conn, db_password_info),
-            _ => return None,
+        Some(())
+    }
+
+    /// Second step of a login: the code for the pending login of `secret` (TOTP, recovery or emailed code), from
+    /// the same device.
+    pub async fn user_login_verify(&self, secret: &str, code: &str, device_id: &str, device_uuid: &str, ip: IpAddr) -> Option<(String, Token)> {
+        let secret_hash = Token::from_str(secret).ok()?.hash();
+
+        let mut state_pending_logins = self.pending_logins.lock().await;
+
+        let pending = state_pending_logins.get_mut(&secret_hash)?;
+        if pending.expires_at <= secs_from_epoch() || pending.attempts >= PENDING_LOGIN_MAX_ATTEMPTS {
+            state_pending_logins.remove(&secret_hash);
+            return None;
+        }
+        pending.attempts += 1;
+
+        if pending.device_id != device_id || pending.device_uuid != device_uuid {
+            return None;
+        }
+
+        let pending = pending.clone();
+
+        drop(state_pending_logins);
+
+        let valid = match &pending.check {
+            LoginCheck::Tfa => self.check_second_factor(pending.user_id, code).await,
+            LoginCheck::Email(expected) => bool::from(expected.as_bytes().ct_eq(code.trim().as_bytes())),
         };
 
-        if !password_info.check(db_password_info) {
+        if !valid {
             return None;
         }
 
-        drop(conn);
+        // The code is valid once.
+        self.pending_logins.lock().await.remove(&secret_hash)?;
+
+        let access_token = self.create_session(pending.user_id, &pending.username, device_id, device_uuid, ip).await?;
+
+        Some((pending.username, access_token))
+    }
+
+    /// A current TOTP code, or a recovery code which is used up.
+    async fn check_second_factor(&self, user_id: UserId, code: &str) -> bool {
+        let db_totp = match self.db.get_user_totp(user_id).await {
+            Some(db_totp) => db_totp,
+            None => return false,
+        };
+
+        if let Some(secret) = db_totp.secret.as_deref().filter(|_| db_totp.enabled) {
+            if let Some(step) = totp::verify(secret, code, secs_from_epoch(), db_totp.last_step) {
+                return self.db.set_user_totp_last_step(user_id, step).await.unwrap_or(false);
+            }
+        }
+
+        self.db.use_recovery_code(user_id, &totp::hash_recovery_code(code)).await.unwrap_or(false)
+    }
+
+    /// Id of an active user with this password, checked by the authentication backends.
+    async fn check_password<'s>(&self, username: &str, password_info: UserPasswordInfo<'s>) -> Option<UserId> {
+        self.auth.verify(username, password_info.password).await
+    }
 
+    async fn create_session(&self, user_id: UserId, username: &str, device_id: &str, device_uuid: &str, ip: IpAddr) -> Option<Token> {
         let access_token = Token::new_random();
+        let token_hash = access_token.hash();
+        let now = secs_from_epoch();
+
+        let session_id = self.db.insert_session(token_hash, user_id, device_id, device_uuid, ip, now).await?;
+        self.db.add_known_device(user_id, device_id, device_uuid, now).await?;
 
         let mut state_access_tokens = self.access_tokens.write().await;
         let mut state_sessions = self.sessions.write().await;
         let mut state_users = self.users.write().await;
 
-        state_sessions.counter += 1;
-        let session_id = state_sessions.counter;
-
         if let Some(user_info) = state_users.get_mut(&user_id) {
             user_info.sessions_count += 1;
         } else {
             let user_info = UserInfo {
                 sessions_count: 1,
-                username: username.clone(),
+                username: username.to_string(),
             };
             state_users.insert( user_id, user_info );
 
-            let mut state_address_books = self.address_books.write().await;
-            if let Some(abi) = state_address_books.get_mut(&user_id) {
-                abi.remove_after_flush = false;
+            let state_personal_books = self.personal_books.read().await;
+            if let Some((book_id, _)) = state_personal_books.get(&user_id) {
+                let mut state_address_books = self.address_books.write().await;
+                if let Some(abi) = state_address_books.get_mut(book_id) {
+                    abi.remove_after_flush = false;
+                }
             }
         }
 
         let session_info = SessionInfo {
             user_id,
+            token_hash,
+            device_id: device_id.to_string(),
+            device_uuid: device_uuid.to_string(),
+            ip: ip.to_string(),
+            created_at: now,
+            last_seen_at: now,
+            modified: false,
         };
 
         let access_token_info = AccessTokenInfo {
             session_id,
             user_id,
+            device_id: device_id.to_string(),
+            device_uuid: device_uuid.to_string(),
         };
 
         let _ = state_sessions.sessions.insert(session_id, session_info);
-        let _ = state_access_tokens.insert( access_token.clone(), access_token_info );
+        let _ = state_access_tokens.insert( token_hash, access_token_info );
+
+        Some(access_token)
+    }
+
+    /// Start enrolment with a new secret, returned base32-encoded. A previous enrolment which wasn't confirmed is replaced.
+    pub async fn tfa_setup(&self, user_id: UserId) -> Result<String, TfaError> {
+        let db_totp = self.db.get_user_totp(user_id).await.ok_or(TfaError::Unavailable)?;
+        if db_totp.enabled {
+            return Err(TfaError::AlreadyEnabled);
+        }
+
+        let secret = totp::new_secret();
+        self.db.set_user_totp_secret(user_id, &secret).await.ok_or(TfaError::Unavailable)?;
+
+        Ok(secret)
+    }
+
+    /// Confirm enrolment with a code from the authenticator. Returns new recovery codes, which are shown only this once.
+    pub async fn tfa_enable(&self, user_id: UserId, code: &str) -> Result<Vec<String>, TfaError> {
+        let db_totp = self.db.get_user_totp(user_id).await.ok_or(TfaError::Unavailable)?;
+        if db_totp.enabled {
+            return Err(TfaError::AlreadyEnabled);
+        }
+
+        let secret = db_totp.secret.ok_or(TfaError::NotEnrolled)?;
+        let step = totp::verify(&secret, code, secs_from_epoch(), 0).ok_or(TfaError::InvalidCode)?;
+
+        let recovery_codes = totp::new_recovery_codes();
+        let code_hashes: Vec<String> = recovery_codes.iter().map(|code| totp::hash_recovery_code(code)).collect();
+
+        self.db.enable_user_totp(user_id, step, &code_hashes).await.ok_or(TfaError::Unavailable)?;
 
-        Some((username.to_string(), access_token))
+        Ok(recovery_codes)
+    }
+
+    /// Turn two-factor authentication off, confirmed with a TOTP or recovery code.
+    pub async fn tfa_disable(&self, user_id: UserId, code: &str) -> Result<(), TfaError> {
+        let db_totp = self.db.get_user_totp(user_id).await.ok_or(TfaError::Unavailable)?;
+        if !db_totp.enabled {
+            return Err(TfaError::NotEnabled);
+        }
+
+        if !self.check_second_factor(user_id, code).await {
+            return Err(TfaError::InvalidCode);
+        }
+
+        self.db.disable_user_totp(user_id).await.ok_or(TfaError::Unavailable)
     }
 
     pub async fn find_session(&self, access_token: &Token) -> Option<AccessTokenInfo> {
         let state_access_tokens = self.access_tokens.read().await;
 
-        state_access_tokens.get( access_token ).map(|t| t.clone())
+        let access_token_info = state_access_tokens.get( &access_token.hash() )?.clone();
+
+        drop(state_access_tokens);
+
+        let now = secs_from_epoch();
+
+        let state_sessions = self.sessions.read().await;
+        let session_info = state_sessions.sessions.get(&access_token_info.session_id)?;
+
+        if self.sessions_config.is_expired(now, session_info.created_at, session_info.last_seen_at) {
+            drop(state_sessions);
+            self.remove_sessions(vec![access_token_info.session_id]).await;
+            return None;
+        }
+
+        // Most requests only need the read lock.
+        if now < session_info.last_seen_at + LAST_SEEN_RESOLUTION {
+            return Some(access_token_info);
+        }
+
+        drop(state_sessions);
+
+        let mut state_sessions = self.sessions.write().await;
+        let session_info = state_sessions.sessions.get_mut(&access_token_info.session_id)?;
+
+        session_info.last_seen_at = session_info.last_seen_at.max(now);
+        session_info.modified = true;
+
+        Some(access_token_info)
     }
 
-    pub async fn get_user_address_book(&self, user_id: UserId) -> Option<AddressBook> {
-        let state_address_books = self.address_books.read().await;
+    /// Id and guid of the user's personal book, created on first use.
+    pub async fn personal_book(&self, user_id: UserId) -> Option<(BookId, String)> {
+        if let Some(book) = self.personal_books.read().await.get(&user_id) {
+            return Some(book.clone());
+        }
+
+        let book = self.db.get_personal_book(user_id).await?;
+        self.personal_books.write().await.insert(user_id, book.clone());
+
+        Some(book)
+    }
 
-        let opt_ab = state_address_books
-            .get(&user_id)
-            .map(|abi| abi.address_book.clone());
+    pub async fn personal_book_id(&self, user_id: UserId) -> Option<BookId> {
+        self.personal_book(user_id).await.map(|(book_id, _)| book_id)
+    }
 
-        if opt_ab.is_some() {
-            return opt_ab;
+    pub async fn get_user_address_book(&self, user_id: UserId) -> Option<AddressBookSnapshot> {
+        let book_id = self.personal_book_id(user_id).await?;
+        self.get_address_book(book_id).await
+    }
+
+    /// Store an uploaded personal book, see [`Self::set_address_book`].
+    pub async fn set_user_address_book(&self, user_id: UserId, address_book: AddressBook, base: Option<&str>) -> Result<AddressBookSnapshot, AddressBookError> {
+        let book_id = self.personal_book_id(user_id).await.ok_or(AddressBookError::Unavailable)?;
+        self.set_address_book(book_id, address_book, base).await
+    }
+
+    pub async fn get_address_book(&self, book_id: BookId) -> Option<AddressBookSnapshot> {
+        let mut state_address_books = self.cached_address_books(book_id).await?;
+
+        let abi = state_address_books.get_mut(&book_id)?;
+        abi.last_access = Instant::now();
+
+        Some(abi.snapshot.clone())
+    }
+
+    /// The locked cache, with the book loaded into it from the database if it wasn't there.
+    async fn cached_address_books(&self, book_id: BookId) -> Option<RwLockWriteGuard<'_, HashMap<BookId, AddressBookInfo>>> {
+        let state_address_books = self.address_books.write().await;
+
+        if state_address_books.contains_key(&book_id) {
+            return Some(state_address_books);
         }
 
         drop(state_address_books);
 
-        let ab = self.db.get_address_book(user_id).await?;
+        let (ab, updated_at) = self.db.get_address_book(book_id).await?;
         let abi = AddressBookInfo {
             modified: false,
             remove_after_flush: false,
-            address_book: ab.clone(),
+            last_access: Instant::now(),
+            snapshot: AddressBookSnapshot::new(ab, updated_at),
+            history: Default::default(),
         };
 
         let mut state_address_books = self.address_books.write().await;
-        state_address_books.insert(user_id, abi.clone());
 
-        Some(ab)
+        // The book could have been uploaded while we were reading the database.
+        state_address_books.entry(book_id).or_insert(abi);
+
+        Self::evict_lru_address_books(&mut state_address_books, self.address_books_config.cache_limit, Some(book_id));
+
+        Some(state_address_books)
     }
 
-    pub async fn set_user_address_book(&self, user_id: UserId, address_book: AddressBook) -> Option<()> {
-        tracing::debug!("set_user_ab()");
-        let mut state_address_books = self.address_books.write().await;
+    /// Store an uploaded book. With `base`, the `If-Match` header of the upload, the book must be based on
+    /// the current version, or be mergeable with it if the config allows merging.
+    pub async fn set_address_book(&self, book_id: BookId, address_book: AddressBook, base: Option<&str>) -> Result<AddressBookSnapshot, AddressBookError> {
+        tracing::debug!("set_ab()");
 
-        if let Some(abi) = state_address_books.get_mut(&user_id) {
-            if abi.address_book != address_book {
-                abi.modified = true;
-                abi.address_book = address_book;
-            };
-        } else {
+        let stored_base = match (base, self.address_books_config.conflicts) {
+            (Some(base), ConflictMode::Merge) => self.find_stored_merge_base(book_id, base).await,
+            _ => None,
+        };
+
+        let mut state_address_books = match base {
+            // The base is checked against the current version, which is loaded if it isn't cached.
+            Some(_) => self.cached_address_books(book_id).await.ok_or(AddressBookError::Unavailable)?,
+            None => self.address_books.write().await,
+        };
+
+        if let Entry::Vacant(entry) = state_address_books.entry(book_id) {
+            // Without a base, the upload replaces whatever the current version is.
             let abi = AddressBookInfo {
-                modified: false,
+                modified: true,
                 remove_after_flush: false,
-                address_book,
+                last_access: Instant::now(),
+                snapshot: AddressBookSnapshot::new(address_book.clone(), secs_from_epoch()),
+                history: Default::default(),
             };
-            state_address_books.insert( user_id, abi );
+            entry.insert( abi );
+
+            Self::evict_lru_address_books(&mut state_address_books, self.address_books_config.cache_limit, Some(book_id));
         }
-        // tracing::debug!("set_user_ab() 2");
 
-        // let _ = self.db.update_ab( user_id, &abi.ab ).await;
+        let abi = state_address_books.get_mut(&book_id).ok_or(AddressBookError::Unavailable)?;
+        abi.last_access = Instant::now();
+
+        let address_book = match base {
+            Some(base) if !etag_list_contains(base, &abi.snapshot.etag) => {
+                let merged = match self.address_books_config.conflicts {
+                    ConflictMode::Reject => None,
+                    ConflictMode::Merge => abi.history
+                        .iter()
+                        .rev()
+                        .find(|snapshot| etag_list_contains(base, &snapshot.etag))
+                        .map(|snapshot| &snapshot.address_book)
+                        .or(stored_base.as_ref())
+                        .and_then(|base| AddressBook::merge(base, &abi.snapshot.address_book, &address_book)),
+                };
+
+                match merged {
+                    Some(merged) => merged,
+                    None => {
+                        tracing::debug!("ab conflict: base {:?}, current {:?}", base, abi.snapshot.etag);
+                        return Err(AddressBookError::Conflict);
+                    },
+                }
+            },
+            _ => address_book,
+        };
+
+        abi.update(address_book, self.address_books_config.merge_history);
 
         tracing::debug!("ab done!");
-        Some(())
+        Ok(abi.snapshot.clone())
+    }
+
+    /// The stored revision an upload is based on, if it's neither the cached book nor one of its previous versions,
+    /// e.g. after a restart or an eviction from the cache.
+    async fn find_stored_merge_base(&self, book_id: BookId, base: &str) -> Option<AddressBook> {
+        let state_address_books = self.address_books.read().await;
+        if let Some(abi) = state_address_books.get(&book_id) {
+            let cached = std::iter::once(&abi.snapshot)
+                .chain(abi.history.iter())
+                .any(|snapshot| etag_list_contains(base, &snapshot.etag));
+            if cached {
+                return None;
+            }
+        }
+        drop(state_address_books);
+
+        self.db
+            .get_address_book_revisions(book_id)
+            .await?
+            .into_iter()
+            .find(|address_book| etag_list_contains(base, &address_book.etag()))
+    }
+
+    /// Change the current version of the book, as the incremental address book API does.
+    pub async fn edit_address_book<F>(&self, book_id: BookId, edit: F) -> Result<AddressBookSnapshot, AddressBookError>
+    where
+        F: FnOnce(&mut AddressBook) -> Result<(), AbEditError>,
+    {
+        // Bring the book into the cache, the change applies to the cached version.
+        self.get_address_book(book_id).await.ok_or(AddressBookError::Unavailable)?;
+
+        let mut state_address_books = self.address_books.write().await;
+        let abi = state_address_books.get_mut(&book_id).ok_or(AddressBookError::Unavailable)?;
+        abi.last_access = Instant::now();
+
+        let mut address_book = abi.snapshot.address_book.clone();
+        edit(&mut address_book).map_err(AddressBookError::Rejected)?;
+
+        abi.update(address_book, self.address_books_config.merge_history);
+
+        Ok(abi.snapshot.clone())
     }
 
     pub async fn user_logout(&self, user: &AuthenticatedUser) -> Option<()> {
+        if self.remove_sessions(vec![user.session_id]).await == 0 {
+            return None;
+        }
+
+        Some(())
+    }
+
+    /// Revoke one of the user's sessions. Returns whether the user had such a session.
+    pub async fn revoke_user_session(&self, user_id: UserId, session_id: SessionId) -> bool {
+        let state_sessions = self.sessions.read().await;
+
+        let owned = state_sessions.sessions
+            .get(&session_id)
+            .is_some_and(|session_info| session_info.user_id == user_id);
+
+        drop(state_sessions);
+
+        if !owned {
+            return false;
+        }
+
+        self.remove_sessions(vec![session_id]).await > 0
+    }
+
+    /// Revoke all sessions of the user but the one the request came with. Returns the number of sessions revoked.
+    pub async fn revoke_other_sessions(&self, user: &AuthenticatedUser) -> usize {
+        let state_sessions = self.sessions.read().await;
+
+        let session_ids: Vec<SessionId> = state_sessions.sessions
+            .iter()
+            .filter(|(session_id, session_info)| session_info.user_id == user.user_id && **session_id != user.session_id)
+            .map(|(session_id, _)| *session_id)
+            .collect();
+
+        drop(state_sessions);
+
+        if session_ids.is_empty() {
+            return 0;
+        }
+
+        self.remove_sessions(session_ids).await
+    }
+
+    /// Forget sessions both in memory and in the database. Returns the number of sessions removed from memory.
+    async fn remove_sessions(&self, session_ids: Vec<SessionId>) -> usize {
         let mut state_access_tokens = self.access_tokens.write().await;
         let mut state_sessions = self.sessions.write().await;
         let mut state_users = self.users.write().await;
 
-        let user_info = state_users.get_mut(&user.user_id)?;
-        user_info.sessions_count -= 1;
+        let mut removed = 0;
+
+        for session_id in session_ids.iter() {
+            let session_info = match state_sessions.sessions.remove(session_id) {
+                Some(session_info) => session_info,
+                None => continue,
+            };
+
+            state_access_tokens.remove(&session_info.token_hash);
+            removed += 1;
 
-        if user_info.sessions_count == 0 {
-            state_users.remove(&user.user_id);
+            let user_id = session_info.user_id;
+            let user_info = match state_users.get_mut(&user_id) {
+                Some(user_info) => user_info,
+                None => continue,
+            };
+
+            user_info.sessions_count -= 1;
 
-            let mut state_address_books = self.address_books.write().await;
-            if let Some(abi) = state_address_books.get_mut(&user.user_id) {
-                abi.remove_after_flush = true;
+            if user_info.sessions_count == 0 {
+                state_users.remove(&user_id);
+
+                let state_personal_books = self.personal_books.read().await;
+                if let Some((book_id, _)) = state_personal_books.get(&user_id) {
+                    let mut state_address_books = self.address_books.write().await;
+                    if let Some(abi) = state_address_books.get_mut(book_id) {
+                        abi.remove_after_flush = true;
+                    }
+                }
             }
         }
 
-        state_sessions.sessions.remove(&user.session_id);
-        state_access_tokens.remove(&user.access_token);
+        drop(state_access_tokens);
+        drop(state_sessions);
+        drop(state_users);
 
-        Some(())
+        if self.db.delete_sessions(session_ids).await.is_none() {
+            tracing::error!("Failed to delete sessions");
+        }
+
+        removed
+    }
+
+    /// Queue an audit event; it is written with the next batch.
+    pub async fn add_audit_event(&self, event: AuditEvent) {
+        let mut state_audit_events = self.audit_events.lock().await;
+        state_audit_events.push(event);
+        limit_audit_events(&mut state_audit_events);
+
+        let batch_full = state_audit_events.len() >= AUDIT_EVENTS_BATCH_SIZE;
+        drop(state_audit_events);
+
+        if batch_full {
+            self.maintenance_flush_audit_events().await;
+        }
+    }
+
+    pub async fn find_user_id(&self, username: &str) -> Option<UserId> {
+        self.db.find_user_by_name(username).await.1.map(|(user_id, _)| user_id)
+    }
+
+    pub async fn find_personal_book_id(&self, username: &str) -> Option<BookId> {
+        let user_id = self.find_user_id(username).await?;
+        self.personal_book_id(user_id).await
+    }
+
+    /// A book by its guid, if the user has access to it.
+    pub async fn find_address_book(&self, user_id: UserId, guid: &str) -> Option<(BookId, AbRule)> {
+        self.db.find_book_by_guid(guid, user_id).await
+    }
+
+    /// Shared books the user has access to.
+    pub async fn list_shared_address_books(&self, user_id: UserId) -> Option<Vec<DatabaseBookRecord>> {
+        self.db.list_shared_books(Some(user_id)).await
+    }
+
+    /// Stored revisions of the book, including the version still waiting to be written.
+    pub async fn list_address_book_revisions(&self, book_id: BookId) -> Option<Vec<AbRevisionInfo>> {
+        self.maintenance_flush_address_books().await;
+        self.db.list_address_book_revisions(book_id).await
+    }
+
+    pub async fn get_address_book_revision(&self, book_id: BookId, revision_id: i64) -> Option<AddressBook> {
+        self.db.get_address_book_revision(book_id, revision_id).await
+    }
+
+    /// Make a stored revision the current book. It is written as a new revision.
+    pub async fn restore_address_book_revision(&self, book_id: BookId, revision_id: i64) -> Option<AddressBookSnapshot> {
+        let address_book = self.db.get_address_book_revision(book_id, revision_id).await?;
+        self.set_address_book(book_id, address_book, None).await.ok()
+    }
+
+    /// Stored audit events, including those still waiting to be written.
+    pub async fn find_audit_events(&self, filter: &AuditFilter) -> Option<Vec<AuditEventRecord>> {
+        self.maintenance_flush_audit_events().await;
+        self.db.find_audit_events(filter).await
+    }
+
+    pub async fn find_conn_sessions(&self, filter: &AuditFilter) -> Option<Vec<ConnSessionRecord>> {
+        self.maintenance_flush_audit_events().await;
+        self.db.find_conn_sessions(filter).await
+    }
+
+    pub async fn is_user_admin(&self, user_id: UserId) -> bool {
+        self.db.is_user_admin(user_id).await
+    }
+
+    /// Active sessions of the user, grouped by the device which logged in.
+    pub async fn list_user_sessions(&self, user_id: UserId) -> Vec<DeviceSessions> {
+        let state_sessions = self.sessions.read().await;
+
+        let mut devices: Vec<DeviceSessions> = vec![];
+
+        for (session_id, session_info) in state_sessions.sessions.iter() {
+            if session_info.user_id != user_id {
+                continue;
+            }
+
+            let session = SessionRecord {
+                session_id: *session_id,
+                ip: session_info.ip.clone(),
+                created_at: session_info.created_at,
+                last_seen_at: session_info.last_seen_at,
+            };
+
+            let device = devices
+                .iter_mut()
+                .find(|device| device.device_id == session_info.device_id && device.device_uuid == session_info.device_uuid);
+
+            match device {
+                Some(device) => device.sessions.push(session),
+                None => devices.push(DeviceSessions {
+                    device_id: session_info.device_id.clone(),
+                    device_uuid: session_info.device_uuid.clone(),
+                    sessions: vec![session],
+                }),
+            }
+        }
+
+        drop(state_sessions);
+
+        for device in devices.iter_mut() {
+            device.sessions.sort_unstable_by_key(|session| session.session_id);
+        }
+        devices.sort_unstable_by(|a, b| (&a.device_id, &a.device_uuid).cmp(&(&b.device_id, &b.device_uuid)));
+
+        devices
+    }
+
+    /// Username and display name, as clients show them.
+    pub async fn user_info(&self, username: String) -> api::UserInfo {
+        let display_name = self.auth.display_name(&username).await;
+        api::UserInfo { name: username, display_name }
     }
 
     pub async fn get_current_user_name(&self, user: &AuthenticatedUser) -> Option<String> {
@@ -272,4 +1178,423 @@ impl ApiState {
 
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuthConfig;
+
+    async fn test_state(name: &str, address_books_config: AddressBooksConfig) -> ApiState {
+        restarted(Database::open_temporary(name).await, address_books_config)
+    }
+
+    /// A state on an existing database, as after a restart.
+    fn restarted(db: Database, address_books_config: AddressBooksConfig) -> ApiState {
+        let auth = AuthChain::new(&AuthConfig::default(), &db).unwrap();
+        ApiState::new(db, SessionsConfig::default(), address_books_config, LoginConfig::default(), None, auth)
+    }
+
+    async fn test_book(state: &ApiState, username: &str) -> BookId {
+        let user_id = state.db.create_user(username, "password", true).await.unwrap();
+        state.personal_book_id(user_id).await.unwrap()
+    }
+
+    fn address_book(peers: &[&str]) -> AddressBook {
+        let peers: Vec<String> = peers.iter().map(|id| format!(r#"{{"id":"{}"}}"#, id)).collect();
+        AddressBook::from_json(&format!(r#"{{"tags":[],"peers":[{}]}}"#, peers.join(","))).unwrap()
+    }
+
+    fn ip() -> std::net::IpAddr {
+        "192.0.2.1".parse().unwrap()
+    }
+
+    /// Keeps sent messages as (recipient, body).
+    #[derive(Clone, Default)]
+    struct TestMailSender {
+        sent: Arc<std::sync::Mutex<Vec<(String, String)>>>,
+    }
+
+    #[rocket::async_trait]
+    impl MailSender for TestMailSender {
+        async fn send(&self, to: &str, _subject: &str, body: &str) -> Result<(), String> {
+            self.sent.lock().unwrap().push((to.to_string(), body.to_string()));
+            Ok(())
+        }
+    }
+
+    impl TestMailSender {
+        /// The code in the last message.
+        fn last_code(&self) -> String {
+            let sent = self.sent.lock().unwrap();
+            let (_, body) = sent.last().unwrap();
+            body.split(|c: char| !c.is_ascii_digit()).find(|word| word.len() == EMAIL_CODE_DIGITS).unwrap().to_string()
+        }
+    }
+
+    fn merging(cache_limit: usize) -> AddressBooksConfig {
+        AddressBooksConfig { cache_limit, conflicts: ConflictMode::Merge, ..Default::default() }
+    }
+
+    #[rocket::async_test]
+    async fn revisions() {
+        let state = test_state("revisions", AddressBooksConfig { revisions: 2, ..Default::default() }).await;
+        let book_id = test_book(&state, "alice").await;
+
+        for peers in [&["1"][..], &["1", "2"], &["1", "2", "3"]] {
+            state.set_address_book(book_id, address_book(peers), None).await.unwrap();
+            state.maintenance_flush_address_books().await;
+        }
+
+        let revisions = state.list_address_book_revisions(book_id).await.unwrap();
+        let peers: Vec<usize> = revisions.iter().map(|revision| revision.peers).collect();
+        assert_eq!(peers, [3, 2, 1]);
+
+        // Restoring writes a new revision with the old content.
+        let first = revisions[2].revision_id;
+        let snapshot = state.restore_address_book_revision(book_id, first).await.unwrap();
+        assert_eq!(snapshot.address_book, address_book(&["1"]));
+        state.maintenance_flush_address_books().await;
+
+        state.maintenance_prune_address_book_revisions().await;
+        let revisions = state.list_address_book_revisions(book_id).await.unwrap();
+        let peers: Vec<usize> = revisions.iter().map(|revision| revision.peers).collect();
+        assert_eq!(peers, [1, 3]);
+        assert!(state.get_address_book_revision(book_id, first).await.is_none());
+    }
+
+    #[rocket::async_test]
+    async fn upload_with_base_while_cache_is_full_of_modified_books() {
+        let state = test_state("upload_full_cache", merging(1)).await;
+        let dirty = test_book(&state, "alice").await;
+        let book_id = test_book(&state, "bob").await;
+
+        state.set_address_book(dirty, address_book(&["1"]), None).await.unwrap();
+
+        let base = state.get_address_book(book_id).await.unwrap();
+        let snapshot = state.set_address_book(book_id, address_book(&["2"]), Some(&base.etag)).await.unwrap();
+
+        assert_eq!(snapshot.address_book, address_book(&["2"]));
+    }
+
+    #[rocket::async_test]
+    async fn upload_based_on_evicted_book() {
+        let state = test_state("upload_evicted", merging(1)).await;
+        let book_id = test_book(&state, "alice").await;
+        let other = test_book(&state, "bob").await;
+
+        let base = state.set_address_book(book_id, address_book(&["1", "2"]), None).await.unwrap();
+        state.maintenance_flush_address_books().await;
+        state.get_address_book(other).await.unwrap();
+        assert!(!state.address_books.read().await.contains_key(&book_id));
+
+        // Based on the current version.
+        let snapshot = state.set_address_book(book_id, address_book(&["1", "2", "3"]), Some(&base.etag)).await.unwrap();
+        assert_eq!(snapshot.address_book, address_book(&["1", "2", "3"]));
+
+        state.maintenance_flush_address_books().await;
+        state.get_address_book(other).await.unwrap();
+
+        // Based on a stored revision: only the peer the client added joins the current version.
+        let snapshot = state.set_address_book(book_id, address_book(&["1", "2", "4"]), Some(&base.etag)).await.unwrap();
+        assert_eq!(snapshot.address_book, address_book(&["1", "2", "3", "4"]));
+    }
+
+    #[rocket::async_test]
+    async fn upload_conflict() {
+        let state = test_state("upload_conflict", merging(0)).await;
+        let book_id = test_book(&state, "alice").await;
+
+        let base = state.set_address_book(book_id, address_book(&["1"]), None).await.unwrap();
+        state.set_address_book(book_id, address_book(&[]), Some(&base.etag)).await.unwrap();
+
+        // Changed here, deleted meanwhile.
+        let edited = AddressBook::from_json(r#"{"tags":[],"peers":[{"id":"1","alias":"edited"}]}"#).unwrap();
+        let res = state.set_address_book(book_id, edited, Some(&base.etag)).await;
+        assert!(matches!(res, Err(AddressBookError::Conflict)));
+
+        let res = state.set_address_book(book_id, address_book(&["2"]), Some("\"unknown\"")).await;
+        assert!(matches!(res, Err(AddressBookError::Conflict)));
+    }
+
+    #[rocket::async_test]
+    async fn upload_rejected_without_merging() {
+        let state = test_state("upload_reject", AddressBooksConfig::default()).await;
+        let book_id = test_book(&state, "alice").await;
+
+        let base = state.set_address_book(book_id, address_book(&["1"]), None).await.unwrap();
+        state.set_address_book(book_id, address_book(&["1", "2"]), Some(&base.etag)).await.unwrap();
+
+        let res = state.set_address_book(book_id, address_book(&["1", "3"]), Some(&base.etag)).await;
+        assert!(matches!(res, Err(AddressBookError::Conflict)));
+    }
+
+    #[rocket::async_test]
+    async fn audit_events_are_stored() {
+        let state = test_state("audit_events", AddressBooksConfig::default()).await;
+        let alice = state.db.create_user("alice", "password", true).await.unwrap();
+        let payload = r#"{"Id":7,"action":"new","id":"123456789","ip":"198.51.100.7","uuid":"uuid"}"#;
+        let request = serde_json::from_str(payload).unwrap();
+        state.add_audit_event(AuditEvent::new(1000, ip(), Some(alice), request, payload.to_string())).await;
+
+        // Queued until the next maintenance run, or until someone looks.
+        let filter = AuditFilter { limit: 10, ..Default::default() };
+        assert!(state.db.find_audit_events(&filter).await.unwrap().is_empty());
+
+        let events = state.find_audit_events(&filter).await.unwrap();
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!((event.created_at, event.source_ip.as_str(), event.username.as_deref()), (1000, "192.0.2.1", Some("alice")));
+        assert_eq!((event.conn_id, event.action.as_str(), event.peer_id.as_str()), (7, "new", "123456789"));
+        assert_eq!(event.payload, payload);
+    }
+
+    #[rocket::async_test]
+    async fn eviction_keeps_the_book_being_loaded() {
+        let state = test_state("evict_loading", AddressBooksConfig { cache_limit: 1, ..Default::default() }).await;
+        let dirty = test_book(&state, "alice").await;
+        let book_id = test_book(&state, "bob").await;
+
+        state.set_address_book(dirty, address_book(&["1"]), None).await.unwrap();
+        assert!(state.get_address_book(book_id).await.is_some());
+
+        // Clean books beyond the limit go, least recently used first.
+        state.maintenance_flush_address_books().await;
+        ApiState::evict_lru_address_books(&mut *state.address_books.write().await, 1, None);
+        let books = state.address_books.read().await;
+        assert!(books.contains_key(&book_id));
+        assert!(!books.contains_key(&dirty));
+    }
+
+    #[rocket::async_test]
+    async fn eviction_of_logged_out_users() {
+        let state = test_state("evict_logged_out", AddressBooksConfig::default()).await;
+        let alice_book = test_book(&state, "alice").await;
+        let bob_book = test_book(&state, "bob").await;
+        let alice = state.db.find_user_by_name("alice").await.1.unwrap().0;
+        let bob = state.db.find_user_by_name("bob").await.1.unwrap().0;
+
+        let _token = state.create_session(alice, "alice", "1", "uuid", ip()).await.unwrap();
+        state.get_address_book(alice_book).await.unwrap();
+        // Loaded while bob has no session, e.g. by an admin.
+        state.get_address_book(bob_book).await.unwrap();
+
+        state.maintenance_evict_address_books().await;
+
+        let books = state.address_books.read().await;
+        assert!(books.contains_key(&alice_book));
+        assert!(!books.contains_key(&bob_book));
+        drop(books);
+
+        let personal_books = state.personal_books.read().await;
+        assert!(personal_books.contains_key(&alice));
+        assert!(!personal_books.contains_key(&bob));
+    }
+
+    #[rocket::async_test]
+    async fn sessions_survive_restart() {
+        let state = test_state("restore_sessions", AddressBooksConfig::default()).await;
+        let alice = state.db.create_user("alice", "password", true).await.unwrap();
+        let token = state.create_session(alice, "alice", "1", "uuid", ip()).await.unwrap();
+        let logged_out = state.create_session(alice, "alice", "2", "uuid", ip()).await.unwrap();
+        let session = state.find_session(&logged_out).await.unwrap();
+        let user = AuthenticatedUser { session_id: session.session_id, user_id: alice, device_id: session.device_id, device_uuid: session.device_uuid };
+        state.user_logout(&user).await.unwrap();
+        state.maintenance().await;
+
+        let state = restarted(state.db.clone(), AddressBooksConfig::default());
+        state.restore_sessions().await;
+
+        assert_eq!(state.find_session(&token).await.unwrap().user_id, alice);
+        assert!(state.find_session(&logged_out).await.is_none());
+        assert_eq!(state.users.read().await[&alice].sessions_count, 1);
+    }
+
+    /// Move the creation and the last use of a cached session back by the given seconds.
+    async fn age_session(state: &ApiState, session_id: SessionId, created: u64, last_seen: u64) {
+        let mut state_sessions = state.sessions.write().await;
+        let session_info = state_sessions.sessions.get_mut(&session_id).unwrap();
+        session_info.created_at -= created;
+        session_info.last_seen_at -= last_seen;
+    }
+
+    #[rocket::async_test]
+    async fn session_expiry() {
+        let state = test_state("session_expiry", AddressBooksConfig::default()).await;
+        let config = SessionsConfig::default();
+        let alice = state.db.create_user("alice", "password", true).await.unwrap();
+        let token = state.create_session(alice, "alice", "1", "uuid", ip()).await.unwrap();
+        let session_id = state.find_session(&token).await.unwrap().session_id;
+
+        // Use moves the idle timeout on.
+        age_session(&state, session_id, 0, config.idle_timeout - 1).await;
+        assert!(state.find_session(&token).await.is_some());
+        let state_sessions = state.sessions.read().await;
+        assert!(state_sessions.sessions[&session_id].last_seen_at + 1 >= secs_from_epoch());
+        assert!(state_sessions.sessions[&session_id].modified);
+        drop(state_sessions);
+
+        age_session(&state, session_id, 0, config.idle_timeout).await;
+        assert!(state.find_session(&token).await.is_none());
+        assert!(state.db.load_sessions().await.unwrap().is_empty());
+
+        let token = state.create_session(alice, "alice", "1", "uuid", ip()).await.unwrap();
+        let session_id = state.find_session(&token).await.unwrap().session_id;
+        age_session(&state, session_id, config.lifetime, 0).await;
+        assert!(state.find_session(&token).await.is_none());
+    }
+
+    #[rocket::async_test]
+    async fn sessions_are_bound_to_the_device() {
+        let state = test_state("device_binding", AddressBooksConfig::default()).await;
+        let alice = state.db.create_user("alice", "password", true).await.unwrap();
+        let token = state.create_session(alice, "alice", "123456789", "uuid", ip()).await.unwrap();
+
+        let session = state.find_session(&token).await.unwrap();
+        assert_eq!((session.user_id, session.device_id.as_str(), session.device_uuid.as_str()), (alice, "123456789", "uuid"));
+
+        // The binding survives a restart.
+        let state = restarted(state.db.clone(), AddressBooksConfig::default());
+        state.restore_sessions().await;
+        let session = state.find_session(&token).await.unwrap();
+        assert_eq!((session.device_id.as_str(), session.device_uuid.as_str()), ("123456789", "uuid"));
+    }
+
+    #[rocket::async_test]
+    async fn list_and_revoke_sessions() {
+        let state = test_state("session_management", AddressBooksConfig::default()).await;
+        let alice = state.db.create_user("alice", "password", true).await.unwrap();
+        let bob = state.db.create_user("bob", "password", true).await.unwrap();
+        let mut sessions = vec![];
+        for (device_id, device_uuid) in [("2", "uuid"), ("1", "uuid"), ("2", "uuid"), ("2", "other")] {
+            let token = state.create_session(alice, "alice", device_id, device_uuid, ip()).await.unwrap();
+            sessions.push(state.find_session(&token).await.unwrap().session_id);
+        }
+        let bob_token = state.create_session(bob, "bob", "3", "uuid", ip()).await.unwrap();
+        let bob_session = state.find_session(&bob_token).await.unwrap().session_id;
+
+        let devices = state.list_user_sessions(alice).await;
+        let listed: Vec<(&str, &str, Vec<SessionId>)> = devices
+            .iter()
+            .map(|device| (device.device_id.as_str(), device.device_uuid.as_str(), device.sessions.iter().map(|session| session.session_id).collect()))
+            .collect();
+        assert_eq!(listed, [("1", "uuid", vec![sessions[1]]), ("2", "other", vec![sessions[3]]), ("2", "uuid", vec![sessions[0], sessions[2]])]);
+
+        // Only own sessions can be revoked.
+        assert!(!state.revoke_user_session(alice, bob_session).await);
+        assert!(state.find_session(&bob_token).await.is_some());
+        assert!(state.revoke_user_session(alice, sessions[1]).await);
+        assert!(!state.revoke_user_session(alice, sessions[1]).await);
+
+        let user = AuthenticatedUser { session_id: sessions[0], user_id: alice, device_id: "2".to_string(), device_uuid: "uuid".to_string() };
+        assert_eq!(state.revoke_other_sessions(&user).await, 2);
+        let devices = state.list_user_sessions(alice).await;
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].sessions[0].session_id, sessions[0]);
+        assert_eq!(state.list_user_sessions(bob).await.len(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn email_verification() {
+        let db = Database::open_temporary("email_verification").await;
+        let auth = AuthChain::new(&AuthConfig::default(), &db).unwrap();
+        let login_config = LoginConfig { email_verification: true, ..Default::default() };
+        let mail = TestMailSender::default();
+        let state = ApiState::new(db, SessionsConfig::default(), AddressBooksConfig::default(), login_config, Some(Box::new(mail.clone())), auth);
+        let alice = state.db.create_user("alice", "password", true).await.unwrap();
+        state.db.set_user_email(alice, Some("alice@example.com")).await.unwrap();
+        state.db.create_user("bob", "password", true).await.unwrap();
+        let login = |username, device_id| state.user_login(username, UserPasswordInfo::from_password("password"), device_id, "uuid", ip());
+
+        let secret = match login("alice", "1").await {
+            Some(LoginOutcome::EmailCheckRequired(_, secret)) => secret,
+            _ => panic!("No email verification"),
+        };
+        assert_eq!(mail.sent.lock().unwrap()[0].0, "alice@example.com");
+        let code = mail.last_code();
+
+        // The code only works for the device which logged in.
+        assert!(state.user_login_verify(&secret, &code, "2", "uuid", ip()).await.is_none());
+        assert!(state.user_login_verify(&secret, "wrong", "1", "uuid", ip()).await.is_none());
+        let (username, token) = state.user_login_verify(&secret, &code, "1", "uuid", ip()).await.unwrap();
+        assert_eq!(username, "alice");
+        assert!(state.find_session(&token).await.is_some());
+        assert!(state.user_login_verify(&secret, &code, "1", "uuid", ip()).await.is_none());
+
+        // Known devices, and users without an email address, aren't asked.
+        assert!(matches!(login("alice", "1").await, Some(LoginOutcome::LoggedIn(..))));
+        assert!(matches!(login("bob", "1").await, Some(LoginOutcome::LoggedIn(..))));
+        assert_eq!(mail.sent.lock().unwrap().len(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn email_verification_attempts() {
+        let db = Database::open_temporary("email_verification_attempts").await;
+        let auth = AuthChain::new(&AuthConfig::default(), &db).unwrap();
+        let login_config = LoginConfig { email_verification: true, ..Default::default() };
+        let mail = TestMailSender::default();
+        let state = ApiState::new(db, SessionsConfig::default(), AddressBooksConfig::default(), login_config, Some(Box::new(mail.clone())), auth);
+        let alice = state.db.create_user("alice", "password", true).await.unwrap();
+        state.db.set_user_email(alice, Some("alice@example.com")).await.unwrap();
 
+        let secret = match state.user_login("alice", UserPasswordInfo::from_password("password"), "1", "uuid", ip()).await {
+            Some(LoginOutcome::EmailCheckRequired(_, secret)) => secret,
+            _ => panic!("No email verification"),
+        };
+        for _ in 0..PENDING_LOGIN_MAX_ATTEMPTS {
+            assert!(state.user_login_verify(&secret, "wrong", "1", "uuid", ip()).await.is_none());
+        }
+        assert!(state.user_login_verify(&secret, &mail.last_code(), "1", "uuid", ip()).await.is_none());
+    }
+
+    #[rocket::async_test]
+    async fn sync_drops_sessions_of_disabled_users() {
+        let state = test_state("sync_sessions", AddressBooksConfig::default()).await;
+        let alice = state.db.create_user("alice", "password", true).await.unwrap();
+        let bob = state.db.create_user("bob", "password", true).await.unwrap();
+        let alice_token = state.create_session(alice, "alice", "1", "uuid", ip()).await.unwrap();
+        let bob_token = state.create_session(bob, "bob", "2", "uuid", ip()).await.unwrap();
+
+        state.db.set_user_active(alice, false).await.unwrap();
+        // Until the next run the session stays valid.
+        assert!(state.find_session(&alice_token).await.is_some());
+
+        state.maintenance_sync_sessions().await;
+        assert!(state.find_session(&alice_token).await.is_none());
+        assert!(state.find_session(&bob_token).await.is_some());
+    }
+
+    #[rocket::async_test]
+    async fn maintenance_runs_in_background() {
+        let state = Arc::new(test_state("maintenance_timer", AddressBooksConfig::default()).await);
+        let book_id = test_book(&state, "alice").await;
+        state.set_address_book(book_id, address_book(&["1"]), None).await.unwrap();
+
+        let maintenance = ApiState::spawn_maintenance(state.clone(), Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        maintenance.abort();
+
+        let (stored, _) = state.db.get_address_book(book_id).await.unwrap();
+        assert_eq!(stored, address_book(&["1"]));
+    }
+
+    #[rocket::async_test]
+    async fn shutdown_writes_pending_changes() {
+        let state = test_state("shutdown", AddressBooksConfig::default()).await;
+        let alice = state.db.create_user("alice", "password", true).await.unwrap();
+        let book_id = state.personal_book_id(alice).await.unwrap();
+        let token = state.create_session(alice, "alice", "1", "uuid", ip()).await.unwrap();
+        let session_id = state.find_session(&token).await.unwrap().session_id;
+        let mut state_sessions = state.sessions.write().await;
+        let session_info = state_sessions.sessions.get_mut(&session_id).unwrap();
+        session_info.last_seen_at += 60;
+        session_info.modified = true;
+        drop(state_sessions);
+        state.set_address_book(book_id, address_book(&["1"]), None).await.unwrap();
+
+        state.shutdown().await;
+
+        let (stored, _) = state.db.get_address_book(book_id).await.unwrap();
+        assert_eq!(stored, address_book(&["1"]));
+        let sessions = state.db.load_sessions().await.unwrap();
+        assert!(sessions[0].last_seen_at >= sessions[0].created_at + 60);
+    }
+}
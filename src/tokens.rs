@@ -1,4 +1,5 @@
 use rand::{thread_rng, Rng};
+use sha2::{Sha256, Digest};
 
 const TOKEN_LENGTH: usize = 32;
 
@@ -14,13 +15,42 @@ impl Token {
     }
 
     /// Convert into base64.
-    pub fn to_base64(&self) -> String {
-        base64::encode_config(&self.0, base64::URL_SAFE_NO_PAD)
+    pub fn to_base64(self) -> String {
+        base64::encode_config(self.0, base64::URL_SAFE_NO_PAD)
     }
 
     pub fn from_str<S: AsRef<str>>(str: S) -> Result<Self, base64::DecodeError> {
         let bytes =
             base64::decode_config(str.as_ref(), base64::URL_SAFE_NO_PAD)?;
+        if bytes.len() != TOKEN_LENGTH {
+            return Err(base64::DecodeError::InvalidLength);
+        }
+        let mut buf = [0u8; TOKEN_LENGTH];
+        buf.copy_from_slice(&bytes);
+        Ok(Self(buf))
+    }
+
+    /// Hash of the token, safe to be stored in the database.
+    pub fn hash(&self) -> TokenHash {
+        TokenHash(Sha256::digest(self.0).into())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TokenHash([u8; TOKEN_LENGTH]);
+
+impl TokenHash {
+    /// Convert into base64.
+    pub fn to_base64(self) -> String {
+        base64::encode_config(self.0, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn from_str<S: AsRef<str>>(str: S) -> Result<Self, base64::DecodeError> {
+        let bytes =
+            base64::decode_config(str.as_ref(), base64::URL_SAFE_NO_PAD)?;
+        if bytes.len() != TOKEN_LENGTH {
+            return Err(base64::DecodeError::InvalidLength);
+        }
         let mut buf = [0u8; TOKEN_LENGTH];
         buf.copy_from_slice(&bytes);
         Ok(Self(buf))
@@ -0,0 +1,152 @@
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, Rng};
+use sha1::Sha1;
+use sha2::{Sha256, Digest};
+use subtle::ConstantTimeEq;
+
+/// Seconds per code, as authenticator apps expect by default.
+const TOTP_STEP: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// Codes of this many steps before and after the current one are accepted, for clock drift.
+const TOTP_SKEW: u64 = 1;
+const TOTP_SECRET_LENGTH: usize = 20;
+
+const RECOVERY_CODES_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+/// Issuer shown by authenticator apps.
+const TOTP_ISSUER: &str = "RustDesk";
+
+const BASE32: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+/// A new random secret, base32-encoded as authenticator apps take it.
+pub fn new_secret() -> String {
+    let mut secret = [0u8; TOTP_SECRET_LENGTH];
+    thread_rng().fill(&mut secret);
+    base32::encode(BASE32, &secret)
+}
+
+/// `otpauth://` URI for QR codes.
+pub fn otpauth_uri(username: &str, secret: &str) -> String {
+    let label: String = format!("{}:{}", TOTP_ISSUER, username)
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' | b'@' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+
+    format!("otpauth://totp/{}?secret={}&issuer={}&digits={}&period={}", label, secret, TOTP_ISSUER, TOTP_DIGITS, TOTP_STEP)
+}
+
+fn code_at(secret: &[u8], step: u64) -> String {
+    // HMAC takes keys of any length.
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).unwrap();
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation, RFC 4226.
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+
+    format!("{:0width$}", value % 10u32.pow(TOTP_DIGITS), width = TOTP_DIGITS as usize)
+}
+
+/// Check a code at `now`, seconds since the epoch. Returns the time step of the code, which must be newer
+/// than `last_step`, the step of the last accepted code, so a code can't be used twice.
+pub fn verify(secret: &str, code: &str, now: u64, last_step: u64) -> Option<u64> {
+    let secret = base32::decode(BASE32, secret)?;
+    let code = code.trim();
+
+    let current = now / TOTP_STEP;
+
+    (current.saturating_sub(TOTP_SKEW)..=current + TOTP_SKEW)
+        .filter(|step| *step > last_step)
+        .find(|step| bool::from(code_at(&secret, *step).as_bytes().ct_eq(code.as_bytes())))
+}
+
+/// New one-time codes to log in without the authenticator, e.g. `k3mf-9xq2a`.
+pub fn new_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; RECOVERY_CODE_LENGTH];
+            thread_rng().fill(&mut bytes);
+
+            let code = base32::encode(BASE32, &bytes).to_lowercase();
+            format!("{}-{}", &code[..4], &code[4..RECOVERY_CODE_LENGTH])
+        })
+        .collect()
+}
+
+/// Recovery codes are random, a plain hash is enough to store them.
+pub fn hash_recovery_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    base64::encode_config(Sha256::digest(code), base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-1 secret of RFC 6238, "12345678901234567890".
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    /// Test vectors of RFC 6238, appendix B, cut to 6 digits.
+    const VECTORS: &[(u64, &str)] = &[
+        (59, "287082"),
+        (1111111109, "081804"),
+        (1111111111, "050471"),
+        (1234567890, "005924"),
+        (2000000000, "279037"),
+        (20000000000, "353130"),
+    ];
+
+    #[test]
+    fn rfc6238_vectors() {
+        for (now, code) in VECTORS {
+            assert_eq!(verify(SECRET, code, *now, 0), Some(now / TOTP_STEP), "at {}", now);
+        }
+    }
+
+    #[test]
+    fn skew() {
+        let (now, code) = VECTORS[3];
+
+        assert!(verify(SECRET, code, now - TOTP_STEP, 0).is_some());
+        assert!(verify(SECRET, code, now + TOTP_STEP, 0).is_some());
+        assert!(verify(SECRET, code, now - 2 * TOTP_STEP, 0).is_none());
+        assert!(verify(SECRET, code, now + 2 * TOTP_STEP, 0).is_none());
+    }
+
+    #[test]
+    fn replay() {
+        let (now, code) = VECTORS[3];
+
+        let step = verify(SECRET, code, now, 0).unwrap();
+        assert!(verify(SECRET, code, now, step).is_none());
+        assert!(verify(SECRET, code, now + TOTP_STEP, step).is_none());
+        assert!(verify(SECRET, code, now, step - 1).is_some());
+    }
+
+    #[test]
+    fn wrong_code() {
+        assert!(verify(SECRET, "287083", 59, 0).is_none());
+        assert!(verify(SECRET, "", 59, 0).is_none());
+        assert!(verify("not base32!", "287082", 59, 0).is_none());
+    }
+
+    #[test]
+    fn recovery_code_hash() {
+        let codes = new_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES_COUNT);
+
+        assert_eq!(hash_recovery_code("K3MF-9XQ2A"), hash_recovery_code("k3mf9xq2a"));
+        assert_eq!(hash_recovery_code(&codes[0].to_uppercase()), hash_recovery_code(&codes[0]));
+        assert_ne!(hash_recovery_code(&codes[0]), hash_recovery_code(&codes[1]));
+    }
+}